@@ -0,0 +1,381 @@
+use age::secrecy::SecretString;
+use clap::ValueEnum;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use zip::{write::{FileOptions, SimpleFileOptions}, AesMode, CompressionMethod, ZipArchive, ZipWriter};
+
+use std::{collections::BTreeMap, fs::{self, File}, io::{Cursor, Read, Seek, Write}, path::{Path, PathBuf}};
+
+use crate::{manifest::CompletedExport, verify::{self, Verification}};
+
+/// Environment variable holding the passphrase for encrypted archives, unless --passphrase-env names another
+const ARCHIVE_PASSPHRASE_ENV: &str = "SPOTIFY_EXPORTER_ARCHIVE_PASSPHRASE";
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum ArchiveFormat {
+    Zip,
+    #[value(name = "tar.gz")]
+    TarGz,
+    #[value(name = "tar.zst")]
+    TarZst
+}
+
+/// Compression method of the files within a zip archive
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ZipCompression {
+    Stored,
+    Deflated,
+    Bzip2,
+    Zstd
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Encryption {
+    /// AES-256 encryption of the files within a zip archive
+    Aes,
+    /// age encryption of the whole archive
+    Age
+}
+
+/// Options for the archive created with --zip
+#[derive(clap::Args)]
+pub struct ArchiveArgs {
+    /// format of the archive created with --zip
+    #[arg(long, value_enum, default_value_t = ArchiveFormat::Zip, global = true, requires = "zip")]
+    pub archive_format: ArchiveFormat,
+    /// compression method of the files within a zip archive
+    #[arg(long, value_enum, default_value_t = ZipCompression::Deflated, global = true, requires = "zip")]
+    pub compression: ZipCompression,
+    /// compression level, 1-9 for deflated and bzip2, 0-9 for tar.gz, 1-22 for zstd and tar.zst
+    #[arg(long, global = true, requires = "zip")]
+    pub compression_level: Option<i64>,
+    /// encrypt the archive with a passphrase, aes only applies to zip archives
+    #[arg(long, value_enum, global = true, requires = "zip")]
+    pub encrypt: Option<Encryption>,
+    /// environment variable holding the passphrase of encrypted archives
+    #[arg(long, default_value = ARCHIVE_PASSPHRASE_ENV, global = true)]
+    pub passphrase_env: String,
+    /// file holding the passphrase of encrypted archives, takes precedence over the environment variable
+    #[arg(long, global = true)]
+    pub passphrase_file: Option<PathBuf>,
+    /// keep the export folder after archiving it
+    #[arg(long, global = true, requires = "zip")]
+    pub keep_originals: bool
+}
+
+/// The files of an archive opened for reading
+pub enum OpenArchive {
+    Zip(ZipArchive<Cursor<Vec<u8>>>, Option<String>),
+    Tar(BTreeMap<String, Vec<u8>>)
+}
+
+impl ArchiveArgs {
+
+    /// Checks the options can be used together and a passphrase is available for encryption, so a long export does not fail at the very end
+    pub fn validate(&self) -> Result<(), String> {
+        if self.encrypt == Some(Encryption::Aes) && self.archive_format != ArchiveFormat::Zip {
+            return Err("--encrypt aes only applies to zip archives, use --encrypt age for tar archives".to_string());
+        }
+        if let Some(level) = self.compression_level {
+            let (format, levels) = match (self.archive_format, self.compression) {
+                (ArchiveFormat::Zip, ZipCompression::Stored) => return Err("--compression-level does not apply to stored zip archives".to_string()),
+                (ArchiveFormat::Zip, ZipCompression::Deflated) => ("deflated", 1..=9),
+                (ArchiveFormat::Zip, ZipCompression::Bzip2) => ("bzip2", 1..=9),
+                (ArchiveFormat::Zip, ZipCompression::Zstd) => ("zstd", 1..=22),
+                (ArchiveFormat::TarGz, _) => ("tar.gz", 0..=9),
+                (ArchiveFormat::TarZst, _) => ("tar.zst", 1..=22)
+            };
+            if !levels.contains(&level) {
+                return Err(format!("Compression level {} is not supported for {}, expected {}-{}", level, format, levels.start(), levels.end()));
+            }
+        }
+        if self.encrypt.is_some() && self.passphrase()?.is_none() {
+            return Err(format!("--encrypt needs a passphrase, set {} or use --passphrase-file", self.passphrase_env));
+        }
+
+        Ok(())
+    }
+
+    /// Reads the passphrase from --passphrase-file or the environment variable
+    ///
+    /// # Returns
+    /// The passphrase, or none if neither is set
+    pub fn passphrase(&self) -> Result<Option<String>, String> {
+        if let Some(passphrase_file) = &self.passphrase_file {
+            let passphrase: String = fs::read_to_string(passphrase_file).map_err(|e| format!("Could not read passphrase file {}: {}", passphrase_file.display(), e))?;
+            return Ok(Some(passphrase.trim_end_matches(['\r', '\n']).to_owned()));
+        }
+
+        Ok(std::env::var(&self.passphrase_env).ok().filter(|passphrase| !passphrase.is_empty()))
+    }
+}
+
+impl OpenArchive {
+
+    /// Opens an archive created by archive_export, the format is taken from the file name
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The archive to open
+    /// * `passphrase` - The passphrase to decrypt the archive with, if it is encrypted
+    pub fn open(path: &Path, passphrase: Option<String>) -> Result<Self, String> {
+        let mut file_name: String = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let mut contents: Vec<u8> = fs::read(path).map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+
+        if let Some(decrypted_name) = file_name.strip_suffix(".age") {
+            let passphrase: &String = passphrase.as_ref().ok_or(format!("{} is encrypted, set the passphrase with --passphrase-env or --passphrase-file", path.display()))?;
+            let decryptor = age::Decryptor::new(contents.as_slice()).map_err(|e| format!("{} is not an age encrypted file: {}", path.display(), e))?;
+            let identity: age::scrypt::Identity = age::scrypt::Identity::new(SecretString::from(passphrase.clone()));
+            let mut decrypted: Vec<u8> = Vec::new();
+            decryptor.decrypt(std::iter::once(&identity as &dyn age::Identity))
+                .and_then(|mut reader| reader.read_to_end(&mut decrypted).map_err(age::DecryptError::from))
+                .map_err(|e| format!("Could not decrypt {}: {}", path.display(), e))?;
+
+            contents = decrypted;
+            file_name = decrypted_name.to_owned();
+        }
+
+        if file_name.ends_with(".zip") {
+            let archive: ZipArchive<Cursor<Vec<u8>>> = ZipArchive::new(Cursor::new(contents)).map_err(|e| format!("{} is not a zip archive: {}", path.display(), e))?;
+            Ok(Self::Zip(archive, passphrase))
+        } else if file_name.ends_with(".tar.gz") {
+            read_tar(GzDecoder::new(contents.as_slice())).map(Self::Tar).map_err(|e| format!("{} is not a tar.gz archive: {}", path.display(), e))
+        } else if file_name.ends_with(".tar.zst") {
+            let decoder = zstd::Decoder::new(contents.as_slice()).map_err(|e| format!("{} is not a tar.zst archive: {}", path.display(), e))?;
+            read_tar(decoder).map(Self::Tar).map_err(|e| format!("{} is not a tar.zst archive: {}", path.display(), e))
+        } else {
+            Err(format!("{} is not a .zip, .tar.gz or .tar.zst archive", path.display()))
+        }
+    }
+
+    /// Names of all files in the archive
+    pub fn file_names(&self) -> Vec<String> {
+        match self {
+            Self::Zip(archive, _) => archive.file_names().map(str::to_owned).collect(),
+            Self::Tar(files) => files.keys().cloned().collect()
+        }
+    }
+
+    /// Reads a file of the archive
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the file within the archive
+    pub fn read(&mut self, name: &str) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Zip(archive, passphrase) => {
+                let mut contents: Vec<u8> = Vec::new();
+                let zip_file = match passphrase {
+                    Some(passphrase) => archive.by_name_decrypt(name, passphrase.as_bytes()),
+                    None => archive.by_name(name)
+                };
+                zip_file.map_err(|e| e.to_string())?.read_to_end(&mut contents).map_err(|e| e.to_string())?;
+
+                Ok(contents)
+            }
+            Self::Tar(files) => files.get(name).cloned().ok_or("not in the archive".to_string())
+        }
+    }
+}
+
+/// Archives the files of an export next to its folder as <export folder>_exported.<format>
+///
+/// The archive is written to a temporary file and verified before it is moved in place, only then are the
+/// exported files removed, unless asked to keep them. Should anything fail the exported files are left untouched.
+///
+/// # Arguments
+///
+/// * `completed_export` - The export to archive
+/// * `archive_args` - The archive options given on the command line
+///
+/// # Returns
+/// The path of the archive
+pub fn archive_export(completed_export: &CompletedExport, archive_args: &ArchiveArgs) -> Result<PathBuf, String> {
+    archive_args.validate()?;
+    let passphrase: Option<String> = match archive_args.encrypt {
+        Some(_) => archive_args.passphrase()?,
+        None => None
+    };
+
+    let export_dir: &Path = &completed_export.dir;
+    let extension: &str = match archive_args.archive_format {
+        ArchiveFormat::Zip => "zip",
+        ArchiveFormat::TarGz => "tar.gz",
+        ArchiveFormat::TarZst => "tar.zst"
+    };
+    let mut archive_name: String = format!("{}_exported.{}", export_dir.file_name().unwrap().to_string_lossy(), extension);
+    if archive_args.encrypt == Some(Encryption::Age) {
+        archive_name.push_str(".age");
+    }
+    let archive_path: PathBuf = export_dir.with_file_name(&archive_name);
+    // the temporary file keeps the extension so it can be verified like the final archive
+    let temp_path: PathBuf = export_dir.with_file_name(format!(".partial-{}", archive_name));
+
+    if let Err(e) = write_verified_archive(&temp_path, completed_export, archive_args, passphrase) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    fs::rename(&temp_path, &archive_path).map_err(|e| format!("Could not move {} to {}: {}", temp_path.display(), archive_path.display(), e))?;
+
+    if !archive_args.keep_originals {
+        for file_name in &completed_export.files {
+            fs::remove_file(export_dir.join(file_name)).map_err(|e| format!("Could not remove {}: {}", file_name, e))?;
+        }
+        // files that were not written by the export are left alone, along with the folder holding them
+        let _ = fs::remove_dir(export_dir);
+    }
+
+    Ok(archive_path)
+}
+
+/// Writes the archive and checks it against the manifest of the export
+///
+/// # Arguments
+///
+/// * `path` - Where to write the archive to
+/// * `completed_export` - The export to archive
+/// * `archive_args` - The archive options given on the command line
+/// * `passphrase` - The passphrase to encrypt the archive with, if it is encrypted
+fn write_verified_archive(path: &Path, completed_export: &CompletedExport, archive_args: &ArchiveArgs, passphrase: Option<String>) -> Result<(), String> {
+    let to_error = |e: std::io::Error| format!("Could not write {}: {}", path.display(), e);
+    let file: File = if archive_args.encrypt == Some(Encryption::Age) {
+        // age streams are not seekable as zip needs, so the archive is built in memory before being encrypted
+        let archive: Vec<u8> = write_archive(Cursor::new(Vec::new()), completed_export, archive_args, None)?.into_inner();
+        let encryptor: age::Encryptor = age::Encryptor::with_user_passphrase(SecretString::from(passphrase.clone().unwrap()));
+        let mut writer = encryptor.wrap_output(File::create(path).map_err(to_error)?).map_err(to_error)?;
+        writer.write_all(&archive).map_err(to_error)?;
+        writer.finish().map_err(to_error)?
+    } else {
+        write_archive(File::create(path).map_err(to_error)?, completed_export, archive_args, passphrase.as_deref())?
+    };
+    file.sync_all().map_err(to_error)?;
+
+    let verification: Verification = verify::verify_export(path, passphrase)?;
+    if !verification.problems.is_empty() {
+        return Err(format!("the archive does not match the export({})", verification.problems.join(", ")));
+    }
+
+    Ok(())
+}
+
+/// Writes the files into an archive of the chosen format
+///
+/// # Arguments
+///
+/// * `writer` - Where to write the archive to
+/// * `completed_export` - The export to archive
+/// * `archive_args` - The archive options given on the command line
+/// * `aes_passphrase` - The passphrase to encrypt the files of a zip archive with, if any
+fn write_archive<W: Write + Seek>(writer: W, completed_export: &CompletedExport, archive_args: &ArchiveArgs, aes_passphrase: Option<&str>) -> Result<W, String> {
+    let (export_dir, file_names) = (completed_export.dir.as_path(), completed_export.files.as_slice());
+    match archive_args.archive_format {
+        ArchiveFormat::Zip => {
+            let compression_method: CompressionMethod = match archive_args.compression {
+                ZipCompression::Stored => CompressionMethod::Stored,
+                ZipCompression::Deflated => CompressionMethod::Deflated,
+                ZipCompression::Bzip2 => CompressionMethod::Bzip2,
+                ZipCompression::Zstd => CompressionMethod::Zstd
+            };
+            let mut options: FileOptions<()> = SimpleFileOptions::default().compression_method(compression_method).compression_level(archive_args.compression_level);
+            if let Some(passphrase) = aes_passphrase {
+                options = options.with_aes_encryption(AesMode::Aes256, passphrase);
+            }
+
+            let mut zip_writer: ZipWriter<W> = ZipWriter::new(writer);
+            for file_name in file_names {
+                zip_writer.start_file(file_name.as_str(), options).map_err(|e| format!("Could not add {} to the archive: {}", file_name, e))?;
+                let mut to_zip_file: File = File::open(export_dir.join(file_name)).map_err(|e| format!("Could not read {}: {}", file_name, e))?;
+                std::io::copy(&mut to_zip_file, &mut zip_writer).map_err(|e| format!("Could not add {} to the archive: {}", file_name, e))?;
+            }
+
+            zip_writer.finish().map_err(|e| format!("Could not complete the archive: {}", e))
+        }
+        ArchiveFormat::TarGz => {
+            let level: u32 = archive_args.compression_level.map_or(Compression::default().level(), |level| level as u32);
+
+            let encoder: GzEncoder<W> = write_tar(GzEncoder::new(writer, Compression::new(level)), export_dir, file_names)?;
+            encoder.finish().map_err(|e| format!("Could not complete the archive: {}", e))
+        }
+        ArchiveFormat::TarZst => {
+            let level: i32 = archive_args.compression_level.map_or(zstd::DEFAULT_COMPRESSION_LEVEL, |level| level as i32);
+
+            let encoder = zstd::Encoder::new(writer, level).map_err(|e| format!("Could not start the archive: {}", e))?;
+            write_tar(encoder, export_dir, file_names)?.finish().map_err(|e| format!("Could not complete the archive: {}", e))
+        }
+    }
+}
+
+/// Writes the files into a tar stream
+fn write_tar<W: Write>(writer: W, export_dir: &Path, file_names: &[String]) -> Result<W, String> {
+    let mut tar_builder: tar::Builder<W> = tar::Builder::new(writer);
+    for file_name in file_names {
+        tar_builder.append_path_with_name(export_dir.join(file_name), file_name).map_err(|e| format!("Could not add {} to the archive: {}", file_name, e))?;
+    }
+
+    tar_builder.into_inner().map_err(|e| format!("Could not complete the archive: {}", e))
+}
+
+/// Reads every file of a tar stream into memory
+fn read_tar<R: Read>(reader: R) -> std::io::Result<BTreeMap<String, Vec<u8>>> {
+    let mut files: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+    for entry in tar::Archive::new(reader).entries()? {
+        let mut entry = entry?;
+        let name: String = entry.path()?.to_string_lossy().into_owned();
+        let mut contents: Vec<u8> = Vec::new();
+        entry.read_to_end(&mut contents)?;
+        files.insert(name, contents);
+    }
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive_args(archive_format: ArchiveFormat, compression: ZipCompression, compression_level: i64) -> ArchiveArgs {
+        ArchiveArgs {
+            archive_format,
+            compression,
+            compression_level: Some(compression_level),
+            encrypt: None,
+            passphrase_env: ARCHIVE_PASSPHRASE_ENV.to_string(),
+            passphrase_file: None,
+            keep_originals: false
+        }
+    }
+
+    #[test]
+    fn accepted_compression_levels_match_the_writers() {
+        let export_dir: PathBuf = std::env::temp_dir().join(format!("spotify_exporter_archive_{}", std::process::id()));
+        fs::create_dir_all(&export_dir).unwrap();
+        fs::write(export_dir.join("tracks.json"), "{\"tracks\": []}").unwrap();
+        let completed_export: CompletedExport = CompletedExport { dir: export_dir.clone(), files: vec!["tracks.json".to_string()] };
+
+        let combinations: [(ArchiveFormat, ZipCompression); 5] = [
+            (ArchiveFormat::Zip, ZipCompression::Deflated),
+            (ArchiveFormat::Zip, ZipCompression::Bzip2),
+            (ArchiveFormat::Zip, ZipCompression::Zstd),
+            (ArchiveFormat::TarGz, ZipCompression::Deflated),
+            (ArchiveFormat::TarZst, ZipCompression::Deflated)
+        ];
+        let mut mismatches: Vec<String> = Vec::new();
+        for (archive_format, compression) in combinations {
+            for level in 0..=22 {
+                let archive_args: ArchiveArgs = archive_args(archive_format, compression, level);
+                // the writers reject or even panic on some levels outside the accepted range, accepted levels must always be written
+                if archive_args.validate().is_ok() && write_archive(Cursor::new(Vec::new()), &completed_export, &archive_args, None).is_err() {
+                    let format: String = archive_format.to_possible_value().unwrap().get_name().to_owned();
+                    mismatches.push(format!("{} {} level {}", format, compression.to_possible_value().unwrap().get_name(), level));
+                }
+            }
+        }
+        // zip rejects deflated level 0 only once the export is archived, which validate is there to prevent
+        let deflated_level_0: ArchiveArgs = archive_args(ArchiveFormat::Zip, ZipCompression::Deflated, 0);
+        let deflated_level_0_written: bool = write_archive(Cursor::new(Vec::new()), &completed_export, &deflated_level_0, None).is_ok();
+        fs::remove_dir_all(&export_dir).unwrap();
+
+        assert_eq!(mismatches, Vec::<String>::new());
+        assert!(deflated_level_0.validate().is_err());
+        assert!(!deflated_level_0_written);
+    }
+}
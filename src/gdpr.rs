@@ -0,0 +1,475 @@
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, Utc};
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+
+use std::{collections::{HashMap, HashSet}, fs, path::{Path, PathBuf}};
+
+use crate::{archive::OpenArchive, manifest::{CompletedExport, ExportRun, ExportSource, Manifest, MANIFEST_FILE}};
+
+/// Folder within the output directory of a profile the data package is imported into
+pub const GDPR_DIR: &str = "gdpr";
+
+/// A single play of the streaming history in the data package
+#[derive(Serialize, Deserialize)]
+pub struct Play {
+    /// When playback ended, RFC 3339 in UTC
+    pub ended_at: String,
+    pub ms_played: u64,
+    pub track_name: Option<String>,
+    pub artist_name: Option<String>,
+    pub album_name: Option<String>,
+    pub show_name: Option<String>,
+    pub episode_name: Option<String>,
+    /// URI of the track or episode, for plays of the basic history only known when it could be reconciled
+    pub uri: Option<String>,
+    pub reason_start: Option<String>,
+    pub reason_end: Option<String>,
+    pub skipped: Option<bool>,
+    pub shuffle: Option<bool>,
+    pub platform: Option<String>,
+    pub country: Option<String>,
+    /// Whether the play comes from the extended streaming history, the basic one only has minute precision
+    pub extended: bool
+}
+
+/// What was read from the data package, parts missing from the package are none
+#[derive(Default)]
+struct Package {
+    plays: Vec<Play>,
+    library: Option<Value>,
+    playlists: Option<Vec<Value>>,
+    /// The user name and country of the account
+    account: Option<(String, Option<String>)>,
+    /// Entries that could not be read, by file
+    unreadable: Vec<String>
+}
+
+/// Tracks to reconcile the entries of the data package with, by URI and by artist and track name
+#[derive(Default)]
+struct TrackIndex {
+    uris: HashSet<String>,
+    by_name: HashMap<String, String>
+}
+
+impl TrackIndex {
+
+    /// Adds a track object as returned by Spotify
+    fn add_track(&mut self, track: &Value) {
+        let uri: Option<String> = track["uri"].as_str().map(str::to_owned).or(track["id"].as_str().map(|id| format!("spotify:track:{}", id)));
+        if let (Some(uri), Some(artist), Some(name)) = (uri, track["artists"][0]["name"].as_str(), track["name"].as_str()) {
+            self.add(uri, artist, name);
+        }
+    }
+
+    fn add(&mut self, uri: String, artist: &str, track: &str) {
+        self.by_name.entry(name_key(artist, track)).or_insert(uri.clone());
+        self.uris.insert(uri);
+    }
+
+    /// Finds a track by URI, or by artist and track name if the URI is unknown or differs
+    ///
+    /// # Returns
+    /// The URI of the track in the index and how it was matched
+    fn find(&self, uri: Option<&str>, artist: Option<&str>, track: Option<&str>) -> Option<(String, &'static str)> {
+        if let Some(uri) = uri.filter(|uri| self.uris.contains(*uri)) {
+            return Some((uri.to_owned(), "uri"));
+        }
+
+        // tracks are relinked to other URIs over time, the name still identifies them
+        let (artist, track) = (artist?, track?);
+        self.by_name.get(&name_key(artist, track)).map(|uri| (uri.clone(), "name"))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.uris.is_empty()
+    }
+}
+
+/// Imports Spotify's "Download your data" package into the output directory, merging it with earlier imports
+/// and reconciling its entries with the latest API export by URI or by artist and track name
+///
+/// The streaming history of earlier imports is kept, so packages covering different periods add up.
+/// The library and playlists are replaced by those of the package, when it holds them.
+///
+/// # Arguments
+///
+/// * `package_path` - The package as downloaded(zip) or extracted(folder)
+/// * `import_dir` - The folder the import is kept in
+/// * `profile` - The name of the profile imported into
+/// * `api_export` - The latest API export of the profile, if there is one
+///
+/// # Returns
+/// The import folder and its files
+pub fn import_package(package_path: &Path, import_dir: &Path, profile: &str, api_export: Option<ExportSource>) -> Result<CompletedExport, String> {
+    let mut package: Package = read_package(package_path)?;
+    if package.plays.is_empty() && package.library.is_none() && package.playlists.is_none() {
+        return Err(format!("{} holds no streaming history, library or playlists", package_path.display()));
+    }
+    println!("Read {} plays, {} and {} playlists from the package", package.plays.len(),
+        if package.library.is_some() { "the library" } else { "no library" },
+        package.playlists.as_ref().map_or(0, Vec::len));
+
+    let mut previous_source: Option<ExportSource> = import_dir.join(MANIFEST_FILE).is_file().then(|| ExportSource::Dir(import_dir.to_owned()));
+    let previous_manifest: Option<Manifest> = previous_source.as_mut().map(ExportSource::read_manifest).transpose()?;
+    if let (Some(previous_source), Some(previous_manifest)) = (previous_source.as_mut(), previous_manifest.as_ref()) {
+        let previous_plays: Value = previous_source.read_category(previous_manifest, "streaming_history")?;
+        let previous_plays: Vec<Play> = serde_json::from_value(previous_plays).map_err(|e| format!("The streaming history of the earlier import is not valid: {}", e))?;
+        println!("Merging with the {} plays of the earlier import", previous_plays.len());
+        package.plays.extend(previous_plays);
+
+        if package.library.is_none() {
+            package.library = Some(previous_source.read_category(previous_manifest, "library")?).filter(|library| !library.is_null());
+        }
+        if package.playlists.is_none() {
+            package.playlists = previous_source.read_category(previous_manifest, "gdpr_playlists")?.as_array().cloned();
+        }
+    }
+    let mut plays: Vec<Play> = merge_plays(package.plays);
+
+    // the extended history knows the URIs the basic history lacks
+    let mut history_index: TrackIndex = TrackIndex::default();
+    for play in plays.iter().filter(|play| play.extended) {
+        if let (Some(uri), Some(artist), Some(track)) = (&play.uri, &play.artist_name, &play.track_name) {
+            history_index.add(uri.clone(), artist, track);
+        }
+    }
+    let export_index: TrackIndex = match api_export {
+        Some(mut api_export) => index_export(&mut api_export)?,
+        None => TrackIndex::default()
+    };
+
+    let mut reconciled_plays: usize = 0;
+    for play in plays.iter_mut().filter(|play| play.uri.is_none() && play.track_name.is_some()) {
+        let artist: Option<&str> = play.artist_name.as_deref();
+        let track: Option<&str> = play.track_name.as_deref();
+        if let Some((uri, _)) = export_index.find(None, artist, track).or_else(|| history_index.find(None, artist, track)) {
+            play.uri = Some(uri);
+            reconciled_plays += 1;
+        }
+    }
+    if reconciled_plays > 0 {
+        println!("{} plays without URI reconciled by artist and track name", reconciled_plays);
+    }
+
+    if export_index.is_empty() {
+        println!("No API export found, the library and playlists are not reconciled");
+    } else {
+        if let Some(library) = package.library.as_mut() {
+            let tracks = library["tracks"].as_array_mut().into_iter().flatten();
+            let (matched, by_name, total) = reconcile(&export_index, tracks, "uri", "artist", "track");
+            println!("{} of {} library tracks found in the latest export, {} of them by name", matched, total, by_name);
+        }
+        if let Some(playlists) = package.playlists.as_mut() {
+            let tracks = playlists.iter_mut()
+                .flat_map(|playlist| playlist["items"].as_array_mut().into_iter().flatten())
+                .map(|item| &mut item["track"])
+                .filter(|track| track.is_object());
+            let (matched, by_name, total) = reconcile(&export_index, tracks, "trackUri", "artistName", "trackName");
+            println!("{} of {} playlist tracks found in the latest export, {} of them by name", matched, total, by_name);
+        }
+    }
+
+    let mut import_run: ExportRun = ExportRun::start_in(import_dir.to_owned(), Local::now(), profile)?;
+    // the package names the account in Userdata.json, without it the account of the earlier import is kept
+    if let Some((account_id, market)) = package.account.or(previous_manifest.as_ref().and_then(|manifest| manifest.account_id.clone().map(|account_id| (account_id, manifest.market.clone())))) {
+        import_run.set_account(account_id, market);
+    }
+    for unreadable in package.unreadable {
+        import_run.warn(unreadable);
+    }
+
+    import_run.write_category("streaming_history", plays.len(), None, &json!({ "streaming_history": plays }))?;
+    if let Some(library) = package.library {
+        let items: usize = library.as_object().map_or(0, |library| library.values().filter_map(Value::as_array).map(Vec::len).sum());
+        import_run.write_category("library", items, None, &json!({ "library": library }))?;
+    }
+    if let Some(playlists) = package.playlists {
+        import_run.write_category("gdpr_playlists", playlists.len(), None, &json!({ "gdpr_playlists": playlists }))?;
+    }
+    let completed_import: CompletedExport = import_run.finish()?;
+
+    // files of the earlier import that were not written again are outdated
+    for manifest_category in previous_manifest.iter().flat_map(|manifest| &manifest.categories) {
+        if !completed_import.files.contains(&manifest_category.file) {
+            fs::remove_file(import_dir.join(&manifest_category.file)).map_err(|e| format!("Could not remove {}: {}", manifest_category.file, e))?;
+        }
+    }
+
+    Ok(completed_import)
+}
+
+/// Reads the streaming history imported into the folder
+///
+/// # Arguments
+///
+/// * `import_dir` - The folder the data package was imported into
+///
+/// # Returns
+/// The plays, oldest first
+pub fn read_streaming_history(import_dir: &Path) -> Result<Vec<Play>, String> {
+    if !import_dir.join(MANIFEST_FILE).is_file() {
+        return Err(format!("No data package was imported into {}, import one with import-gdpr first", import_dir.display()));
+    }
+
+    let mut import_source: ExportSource = ExportSource::Dir(import_dir.to_owned());
+    let manifest: Manifest = import_source.read_manifest()?;
+    let plays: Value = import_source.read_category(&manifest, "streaming_history")?;
+    serde_json::from_value(plays).map_err(|e| format!("The imported streaming history is not valid: {}", e))
+}
+
+/// Reads the files of the package that are imported, from the zip or the extracted folder
+fn read_package(package_path: &Path) -> Result<Package, String> {
+    let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+    if package_path.is_dir() {
+        read_dir_recursive(package_path, &mut files).map_err(|e| format!("Could not read {}: {}", package_path.display(), e))?;
+    } else {
+        let mut archive: OpenArchive = OpenArchive::open(package_path, None)?;
+        for name in archive.file_names().into_iter().filter(|name| is_package_file(name)) {
+            let contents: Vec<u8> = archive.read(&name).map_err(|e| format!("{}: {}", name, e))?;
+            files.push((name, contents));
+        }
+    }
+
+    let mut package: Package = Package::default();
+    for (name, contents) in files {
+        let data: Value = serde_json::from_slice(&contents).map_err(|e| format!("{} is not valid JSON: {}", name, e))?;
+        let base_name: &str = base_name(&name);
+
+        if base_name.starts_with("StreamingHistory") || base_name.starts_with("Streaming_History_Audio") || base_name.starts_with("endsong") {
+            let entries: &Vec<Value> = data.as_array().ok_or(format!("{}: expected a list of plays", name))?;
+            let plays: Vec<Play> = entries.iter().filter_map(|entry| if base_name.starts_with("StreamingHistory") { basic_play(entry) } else { extended_play(entry) }).collect();
+            if plays.len() < entries.len() {
+                package.unreadable.push(format!("{}: {} of {} plays could not be read", name, entries.len() - plays.len(), entries.len()));
+            }
+            package.plays.extend(plays);
+        } else if base_name.starts_with("Playlist") {
+            package.playlists.get_or_insert_with(Vec::new).extend(data["playlists"].as_array().cloned().unwrap_or_default());
+        } else if base_name == "YourLibrary.json" {
+            package.library = Some(data);
+        } else if base_name == "Userdata.json" {
+            package.account = data["username"].as_str().map(|username| (username.to_owned(), data["country"].as_str().map(str::to_owned)));
+        }
+    }
+
+    Ok(package)
+}
+
+fn read_dir_recursive(dir: &Path, files: &mut Vec<(String, Vec<u8>)>) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path: PathBuf = entry?.path();
+        if path.is_dir() {
+            read_dir_recursive(&path, files)?;
+        } else if is_package_file(&path.to_string_lossy()) {
+            files.push((path.to_string_lossy().into_owned(), fs::read(&path)?));
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the file is one of the package files that are imported, video history is left out
+fn is_package_file(name: &str) -> bool {
+    let base_name: &str = base_name(name);
+    base_name.ends_with(".json")
+        && ["StreamingHistory", "Streaming_History_Audio", "endsong", "Playlist", "YourLibrary.json", "Userdata.json"].iter().any(|prefix| base_name.starts_with(prefix))
+}
+
+fn base_name(name: &str) -> &str {
+    name.rsplit(['/', '\\']).next().unwrap_or(name)
+}
+
+/// Reads a play of the basic streaming history, which has either artist and track or show and episode names
+fn basic_play(entry: &Value) -> Option<Play> {
+    let ended_at: NaiveDateTime = NaiveDateTime::parse_from_str(entry["endTime"].as_str()?, "%Y-%m-%d %H:%M").ok()?;
+    let is_episode: bool = entry["podcastName"].is_string();
+
+    Some(Play {
+        ended_at: ended_at.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+        ms_played: entry["msPlayed"].as_u64()?,
+        track_name: if is_episode { None } else { string_field(entry, "trackName") },
+        artist_name: if is_episode { None } else { string_field(entry, "artistName") },
+        album_name: None,
+        show_name: string_field(entry, "podcastName"),
+        episode_name: string_field(entry, "episodeName"),
+        uri: None,
+        reason_start: None,
+        reason_end: None,
+        skipped: None,
+        shuffle: None,
+        platform: None,
+        country: None,
+        extended: false
+    })
+}
+
+/// Reads a play of the extended streaming history
+fn extended_play(entry: &Value) -> Option<Play> {
+    let ended_at: DateTime<FixedOffset> = DateTime::parse_from_rfc3339(entry["ts"].as_str()?).ok()?;
+
+    Some(Play {
+        ended_at: ended_at.with_timezone(&Utc).format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+        ms_played: entry["ms_played"].as_u64()?,
+        track_name: string_field(entry, "master_metadata_track_name"),
+        artist_name: string_field(entry, "master_metadata_album_artist_name"),
+        album_name: string_field(entry, "master_metadata_album_album_name"),
+        show_name: string_field(entry, "episode_show_name"),
+        episode_name: string_field(entry, "episode_name"),
+        uri: string_field(entry, "spotify_track_uri").or(string_field(entry, "spotify_episode_uri")),
+        reason_start: string_field(entry, "reason_start"),
+        reason_end: string_field(entry, "reason_end"),
+        skipped: entry["skipped"].as_bool(),
+        shuffle: entry["shuffle"].as_bool(),
+        platform: string_field(entry, "platform"),
+        country: string_field(entry, "conn_country"),
+        extended: true
+    })
+}
+
+fn string_field(entry: &Value, field: &str) -> Option<String> {
+    entry[field].as_str().filter(|value| !value.is_empty()).map(str::to_owned)
+}
+
+/// Removes duplicate plays, from packages imported more than once or covered by both histories, preferring the extended history
+///
+/// # Returns
+/// The plays, oldest first
+fn merge_plays(plays: Vec<Play>) -> Vec<Play> {
+    let (extended, basic): (Vec<Play>, Vec<Play>) = plays.into_iter().partition(|play| play.extended);
+
+    let mut seen_plays: HashSet<String> = HashSet::new();
+    let mut merged: Vec<Play> = extended.into_iter().filter(|play| seen_plays.insert(play_key(play, &play.ended_at))).collect();
+
+    // the basic history only has the minute playback ended in, it is compared with the extended history at that precision
+    let extended_minutes: HashSet<String> = merged.iter().map(|play| play_key(play, &play.ended_at[..16])).collect();
+    merged.extend(basic.into_iter().filter(|play| !extended_minutes.contains(&play_key(play, &play.ended_at[..16])) && seen_plays.insert(play_key(play, &play.ended_at))));
+
+    merged.sort_by(|a, b| a.ended_at.cmp(&b.ended_at));
+    merged
+}
+
+fn play_key(play: &Play, ended_at: &str) -> String {
+    format!("{}|{}|{}", ended_at, play.track_name.as_deref().or(play.episode_name.as_deref()).unwrap_or_default(), play.ms_played)
+}
+
+fn name_key(artist: &str, track: &str) -> String {
+    format!("{}|{}", artist.to_lowercase(), track.to_lowercase())
+}
+
+/// Indexes the tracks of an API export, from saved tracks, playlists, recently played and top tracks
+fn index_export(api_export: &mut ExportSource) -> Result<TrackIndex, String> {
+    let manifest: Manifest = api_export.read_manifest()?;
+    let mut export_index: TrackIndex = TrackIndex::default();
+
+    for category in ["tracks", "recently_played", "top_tracks"] {
+        for item in api_export.read_category(&manifest, category)?.as_array().into_iter().flatten() {
+            export_index.add_track(&item["track"]);
+        }
+    }
+    for playlist in api_export.read_category(&manifest, "playlists")?.as_array().into_iter().flatten() {
+        for item in playlist["tracks"].as_array().into_iter().flatten() {
+            export_index.add_track(&item["track"]);
+        }
+    }
+
+    Ok(export_index)
+}
+
+/// Marks the tracks of the package with how they were found in the API export, under matched_by, and the URI they were found by if matched by name, under export_uri
+///
+/// # Arguments
+///
+/// * `export_index` - The tracks of the API export
+/// * `tracks` - The track entries of the package
+/// * `uri_field`, `artist_field`, `track_field` - The fields of the entries holding the URI, artist name and track name
+///
+/// # Returns
+/// How many tracks were found, how many of them by name, and how many tracks there are
+fn reconcile<'a>(export_index: &TrackIndex, tracks: impl Iterator<Item = &'a mut Value>, uri_field: &str, artist_field: &str, track_field: &str) -> (usize, usize, usize) {
+    let (mut matched, mut by_name, mut total): (usize, usize, usize) = (0, 0, 0);
+    for track in tracks {
+        total += 1;
+        match export_index.find(track[uri_field].as_str(), track[artist_field].as_str(), track[track_field].as_str()) {
+            Some((uri, matched_by)) => {
+                matched += 1;
+                if matched_by == "name" {
+                    by_name += 1;
+                    track["export_uri"] = Value::String(uri);
+                }
+                track["matched_by"] = Value::String(matched_by.to_owned());
+            }
+            None => track["matched_by"] = Value::Null
+        }
+    }
+
+    (matched, by_name, total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn basic(end_time: &str, track: &str, ms_played: u64) -> Play {
+        basic_play(&json!({ "endTime": end_time, "artistName": "Artist", "trackName": track, "msPlayed": ms_played })).unwrap()
+    }
+
+    fn extended(ts: &str, track: &str, ms_played: u64) -> Play {
+        extended_play(&json!({
+            "ts": ts,
+            "ms_played": ms_played,
+            "master_metadata_track_name": track,
+            "master_metadata_album_artist_name": "Artist",
+            "spotify_track_uri": format!("spotify:track:{}", track)
+        })).unwrap()
+    }
+
+    fn package_plays() -> Vec<Play> {
+        vec![
+            basic("2023-05-01 10:15", "one", 200000),
+            extended("2023-05-01T10:15:42Z", "one", 200000),
+            basic("2023-05-01 10:20", "two", 180000),
+            extended("2023-05-02T08:00:00Z", "three", 90000)
+        ]
+    }
+
+    #[test]
+    fn extended_play_is_preferred_within_the_same_minute() {
+        let plays: Vec<Play> = merge_plays(package_plays());
+
+        let merged: Vec<(&str, bool)> = plays.iter().map(|play| (play.track_name.as_deref().unwrap(), play.extended)).collect();
+        assert_eq!(merged, vec![("one", true), ("two", false), ("three", true)]);
+        assert_eq!(plays[0].ended_at, "2023-05-01T10:15:42Z");
+    }
+
+    #[test]
+    fn reimported_plays_are_merged_once() {
+        // an earlier import is read back from its file before being merged with the package imported again
+        let previous_plays: Vec<Play> = serde_json::from_value(serde_json::to_value(merge_plays(package_plays())).unwrap()).unwrap();
+        let mut plays: Vec<Play> = package_plays();
+        plays.extend(previous_plays);
+        plays.push(basic("2023-05-03 21:00", "four", 60000));
+
+        let merged: Vec<String> = merge_plays(plays).iter().map(|play| play.track_name.clone().unwrap()).collect();
+        assert_eq!(merged, vec!["one", "two", "three", "four"]);
+    }
+
+    #[test]
+    fn tracks_are_matched_by_uri_or_by_name() {
+        let mut export_index: TrackIndex = TrackIndex::default();
+        export_index.add_track(&json!({ "uri": "spotify:track:relinked", "name": "Song", "artists": [{ "name": "Artist" }] }));
+        export_index.add_track(&json!({ "id": "other", "name": "Other", "artists": [{ "name": "Artist" }] }));
+
+        let mut tracks: Vec<Value> = vec![
+            json!({ "uri": "spotify:track:other", "artist": "Someone Else", "track": "Renamed" }),
+            json!({ "uri": "spotify:track:original", "artist": "ARTIST", "track": "song" }),
+            json!({ "uri": "spotify:track:unknown", "artist": "Artist", "track": "Unknown" })
+        ];
+        let counts: (usize, usize, usize) = reconcile(&export_index, tracks.iter_mut(), "uri", "artist", "track");
+
+        assert_eq!(counts, (2, 1, 3));
+        assert_eq!(tracks[0]["matched_by"], "uri");
+        assert!(tracks[0]["export_uri"].is_null());
+        assert_eq!(tracks[1]["matched_by"], "name");
+        assert_eq!(tracks[1]["export_uri"], "spotify:track:relinked");
+        assert!(tracks[2]["matched_by"].is_null());
+    }
+}
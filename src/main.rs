@@ -1,27 +1,126 @@
+mod archive;
+mod gdpr;
+mod manifest;
+mod profile;
+mod restore;
+mod retention;
 mod spotify;
+mod stats;
+mod verify;
 
-use std::{fs::{self, File}, io::Write, path::{Path, PathBuf}, str::FromStr};
+use std::{collections::{HashMap, HashSet}, fs, io::Write, path::{Path, PathBuf}};
 
-use chrono::Local;
-use clap::Parser;
-use config::Config;
+use chrono::DateTime;
+use clap::{Parser, Subcommand};
 use serde::{Serialize, Deserialize};
-use serde_json::Value;
-use zip::write::SimpleFileOptions;
+use serde_json::{json, Map, Value};
 
-use crate::spotify::spotify_client::SpotifyClient;
+use crate::{archive::ArchiveArgs, manifest::{CompletedExport, ExportRun, ExportSource, Manifest, MANIFEST_FILE}, profile::{Profile, DEFAULT_PROFILE}, spotify::{error::SpotifyError, paging::Page, spotify_client::{SpotifyClient, LIBRARY_WRITE_SCOPE, PLAYLIST_WRITE_SCOPE}}, stats::{HistoryReport, LibraryReport, StatsFormat}, verify::Verification};
 
 #[derive(Parser)]
 #[command(version, 
     about="Exports all your saved data from Spotify", 
     long_about = None)]
 struct Args {
-    /// only retrieve refresh token to be used for authorization code flow, no exporting performed
-    #[arg(short, long)]
+    /// profile from properties.toml to use, the top level settings form the default profile
+    #[arg(short, long, global = true)]
+    profile: Option<String>,
+    /// only retrieve refresh token to be used for authorization code flow, always authorizing in the browser again, no exporting performed
+    #[arg(short, long, global = true)]
     token: bool,
-    /// zip exported files
-    #[arg(short, long)]
-    zip: bool
+    /// archive exported files, zip unless --archive-format says otherwise
+    #[arg(short, long, global = true)]
+    zip: bool,
+    /// retrieve the full track list of saved albums whose tracks Spotify only partly includes, such as box sets
+    #[arg(long, global = true)]
+    complete_albums: bool,
+    /// retrieve the episodes of saved shows, with how far each was listened to
+    #[arg(long, global = true)]
+    show_episodes: bool,
+    #[command(flatten)]
+    archive_args: ArchiveArgs,
+    #[command(subcommand)]
+    command: Option<Command>
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// export saved data, this is what runs when no command is given
+    Export {
+        /// export every profile configured in properties.toml one after the other, cannot be combined with --profile
+        #[arg(long)]
+        all_profiles: bool
+    },
+    /// check an export folder or archive against its manifest
+    Verify {
+        /// the export folder or archive to verify
+        path: PathBuf
+    },
+    /// save the tracks, albums, shows, episodes and audiobooks of an export to the library again and follow its artists again
+    Restore {
+        /// the export folder or archive to restore
+        path: PathBuf,
+        /// also recreate the playlists owned by the exported account, with their items in order
+        #[arg(long)]
+        playlists: bool,
+        /// also follow the playlists of others the exported account followed
+        #[arg(long)]
+        follow_playlists: bool,
+        /// only report what would be restored
+        #[arg(long)]
+        dry_run: bool
+    },
+    /// import Spotify's "Download your data" package into the output folder of the profile, reconciled with the latest export
+    ImportGdpr {
+        /// the package zip as downloaded, or the folder it was extracted to
+        path: PathBuf
+    },
+    /// report statistics of the profile
+    Stats {
+        #[command(subcommand)]
+        report: StatsReport
+    },
+    /// remove the exports of the profile that fall outside its keep_last, keep_daily, keep_weekly and keep_monthly settings
+    Prune {
+        /// only list which exports would be kept and removed
+        #[arg(long)]
+        dry_run: bool
+    }
+}
+
+#[derive(Subcommand)]
+enum StatsReport {
+    /// listening statistics of the streaming history imported with import-gdpr
+    History {
+        /// how many artists, tracks, albums and shows to rank
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+        #[arg(long, value_enum, default_value_t = StatsFormat::Table)]
+        format: StatsFormat
+    },
+    /// statistics of the library of an export
+    Library {
+        /// the export folder or archive to report on, the latest export of the profile by default
+        path: Option<PathBuf>,
+        /// how many artists and genres to rank
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+        #[arg(long, value_enum, default_value_t = StatsFormat::Table)]
+        format: StatsFormat
+    }
+}
+
+/// File in the output directory of a profile keeping the recently played tracks between runs
+const RECENTLY_PLAYED_HISTORY_FILE: &str = "recently_played_history.json";
+
+/// Time ranges Spotify computes top items over, about 4 weeks, 6 months and a year
+const TOP_TIME_RANGES: [&str; 3] = ["short_term", "medium_term", "long_term"];
+
+/// What the restore command was asked to restore
+struct RestoreOptions {
+    playlists: bool,
+    follow_playlists: bool,
+    dry_run: bool
 }
 
 // simple structs used to have a better json serialization for file output
@@ -60,261 +159,965 @@ struct ArtistJson {
     artists: Vec<Value>
 }
 
+#[derive(Serialize, Deserialize)]
+struct RecentlyPlayedJson {
+    recently_played: Vec<Value>
+}
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
-    let properties = Config::builder().add_source(config::File::with_name("properties")).build().unwrap();
-    let mut spotify_client: SpotifyClient = SpotifyClient::new(properties.get_string("oauth_flow_type").unwrap(), properties.get_string("spotify_client_id").unwrap(), properties.get_string("spotify_client_secret").unwrap());
-    spotify_client.get_access_token().await.unwrap();
+    match &args.command {
+        None => export_profiles(&load_profiles(), &args, false).await,
+        Some(Command::Export { all_profiles }) => export_profiles(&load_profiles(), &args, *all_profiles).await,
+        Some(Command::Verify { path }) => verify_export(path, &args.archive_args),
+        Some(Command::Restore { path, playlists, follow_playlists, dry_run }) => {
+            let profiles: Vec<Profile> = load_profiles();
+            let restore_options: RestoreOptions = RestoreOptions { playlists: *playlists, follow_playlists: *follow_playlists, dry_run: *dry_run };
+            if let Err(e) = restore_export(select_profile(&profiles, args.profile.as_deref()), path, &args.archive_args, &restore_options).await {
+                eprintln!("Failed to restore {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+        Some(Command::ImportGdpr { path }) => {
+            let profiles: Vec<Profile> = load_profiles();
+            if let Err(e) = import_gdpr(select_profile(&profiles, args.profile.as_deref()), path, &args.archive_args) {
+                eprintln!("Failed to import {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Stats { report }) => {
+            let profiles: Vec<Profile> = load_profiles();
+            if let Err(e) = print_stats(select_profile(&profiles, args.profile.as_deref()), report, &args.archive_args) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Prune { dry_run }) => {
+            let profiles: Vec<Profile> = load_profiles();
+            let profile: &Profile = select_profile(&profiles, args.profile.as_deref());
+            retention::prune_exports(profile.output_dir(), profile.retention_policy(), *dry_run).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            });
+        }
+    }
+}
+
+/// Loads the profiles from properties.toml, exiting with an error if they could not be loaded
+fn load_profiles() -> Vec<Profile> {
+    profile::load_profiles().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    })
+}
+
+/// Exports the selected profile, or all profiles, exiting with an error if any of them failed
+///
+/// # Arguments
+///
+/// * `profiles` - All profiles configured in properties.toml
+/// * `args` - The command line arguments
+/// * `all_profiles` - Whether to export every profile rather than the selected one
+async fn export_profiles(profiles: &[Profile], args: &Args, all_profiles: bool) {
+    // --profile is global so clap cannot tell it conflicts when given before the command
+    if all_profiles && args.profile.is_some() {
+        eprintln!("--all-profiles cannot be used with --profile");
+        std::process::exit(1);
+    }
+    if args.zip {
+        args.archive_args.validate().unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+    }
 
+    let selected_profiles: Vec<&Profile> = if all_profiles {
+        profiles.iter().collect()
+    } else {
+        vec![select_profile(profiles, args.profile.as_deref())]
+    };
+
+    let mut failed_profiles: Vec<&str> = Vec::new();
+    for profile in selected_profiles {
+        if all_profiles {
+            println!("Profile {}", profile.name);
+        }
+
+        if let Err(e) = export_profile(profile, args).await {
+            eprintln!("Failed to export profile {}: {}", profile.name, e);
+            failed_profiles.push(&profile.name);
+        }
+    }
+
+    if !failed_profiles.is_empty() {
+        if all_profiles {
+            eprintln!("Export failed for profiles : {}", failed_profiles.join(", "));
+        }
+        std::process::exit(1);
+    }
+}
+
+/// Finds the profile with the given name, exiting with an error if there is no such profile
+///
+/// # Arguments
+///
+/// * `profiles` - All profiles configured in properties.toml
+/// * `name` - The name of the profile, or none for the default profile
+fn select_profile<'a>(profiles: &'a [Profile], name: Option<&str>) -> &'a Profile {
+    let name: &str = name.unwrap_or(DEFAULT_PROFILE);
+
+    profiles.iter().find(|profile| profile.name == name).unwrap_or_else(|| {
+        let names: Vec<&str> = profiles.iter().map(|profile| profile.name.as_str()).collect();
+        eprintln!("No profile named {} in properties.toml, configured profiles : {}", name, names.join(", "));
+        std::process::exit(1);
+    })
+}
+
+/// Authorizes with Spotify for the profile and exports all its saved data to its output directory
+///
+/// # Arguments
+///
+/// * `profile` - The profile to export
+/// * `args` - The command line arguments
+async fn export_profile(profile: &Profile, args: &Args) -> Result<(), SpotifyError> {
+    let mut spotify_client: SpotifyClient = profile.create_client()?;
     if args.token {
-        println!("Token retrieved and saved, please see token.txt");
-        std::io::stdout().flush().unwrap();
-        return;
+        spotify_client.reauthorize();
     }
+    spotify_client.get_access_token().await?;
 
-    if !Path::new("output").exists() {
-        fs::create_dir("output").unwrap();
+    if args.token {
+        println!("Token retrieved and saved, please see {}", spotify_client.token_location().display());
+        std::io::stdout().flush().unwrap();
+        return Ok(());
     }
 
-    export_saved_tracks(&mut spotify_client).await;
-    export_saved_albums(&mut spotify_client).await;
-    export_saved_audiobooks(&mut spotify_client).await;
-    export_saved_episodes(&mut spotify_client).await;
-    export_user_playlists(&mut spotify_client).await;
-    export_saved_shows(&mut spotify_client).await;
-    export_followed_artists(&mut spotify_client).await;
+    let current_user: Value = spotify_client.get_current_user().await?;
+    let mut export_run: ExportRun = ExportRun::start(profile.output_dir(), &profile.name).map_err(SpotifyError::Export)?;
+    let user_id: &str = current_user["id"].as_str().unwrap();
+    export_run.set_account(user_id.to_owned(), current_user["country"].as_str().map(str::to_owned));
+
+    export_user_profile(&mut export_run, &current_user)?;
+
+    export_saved_tracks(&mut spotify_client, &mut export_run).await?;
+    export_saved_albums(&mut spotify_client, &mut export_run, args.complete_albums).await?;
+    export_saved_audiobooks(&mut spotify_client, &mut export_run).await?;
+    export_saved_episodes(&mut spotify_client, &mut export_run).await?;
+    export_user_playlists(&mut spotify_client, &mut export_run, user_id).await?;
+    export_saved_shows(&mut spotify_client, &mut export_run, args.show_episodes).await?;
+    export_followed_artists(&mut spotify_client, &mut export_run).await?;
+    export_recently_played(&mut spotify_client, &mut export_run, &profile.output_dir().join(RECENTLY_PLAYED_HISTORY_FILE)).await?;
+    export_top_items(&mut spotify_client, &mut export_run, "artists", "artist").await?;
+    export_top_items(&mut spotify_client, &mut export_run, "tracks", "track").await?;
+    export_artists_catalog(&mut spotify_client, &mut export_run).await?;
+
+    let completed_export: CompletedExport = export_run.finish().map_err(SpotifyError::Export)?;
+    println!("Exported to {}", completed_export.dir.display());
 
     if args.zip {
-        zip_exported_json().unwrap();
+        println!("Archiving exported files");
+        let archive_path: PathBuf = archive::archive_export(&completed_export, &args.archive_args).map_err(SpotifyError::Archive)?;
+        println!("Archived to {}", archive_path.display());
     }
+
+    if profile.retention_policy().is_enabled() {
+        println!("Applying retention");
+        retention::prune_exports(profile.output_dir(), profile.retention_policy(), false).map_err(SpotifyError::Retention)?;
+    }
+
+    Ok(())
 }
 
-async fn export_saved_tracks(spotify_client: &mut SpotifyClient) {
+async fn export_saved_tracks(spotify_client: &mut SpotifyClient, export_run: &mut ExportRun) -> Result<(), SpotifyError> {
     println!("Exporting saved tracks");
     print!("\rProcessing 0%");
     std::io::stdout().flush().unwrap();
 
     // retrieve first 50 tracks
-    let mut tracks_vector: Vec<Value> = Vec::new();
-    let mut spotify_track_response: Value = spotify_client.get_saved_tracks(0, 50).await.unwrap();
-    tracks_vector.append(&mut spotify_track_response["items"].as_array().unwrap().clone());
+    let mut spotify_track_page: Page = spotify_client.get_saved_tracks(0, 50).await?;
+    let mut tracks_vector: Vec<Value> = std::mem::take(&mut spotify_track_page.items);
 
     // keep retrieving tracks until our count = total in spotify response
-    while tracks_vector.len() < usize::try_from(spotify_track_response["total"].as_i64().unwrap()).unwrap() {
-        spotify_track_response = spotify_client.get_saved_tracks(tracks_vector.len().try_into().unwrap(), 50).await.unwrap();
-        tracks_vector.append(&mut spotify_track_response["items"].as_array().unwrap().clone());
+    while tracks_vector.len() < spotify_track_page.total {
+        spotify_track_page = spotify_client.get_saved_tracks(tracks_vector.len().try_into().unwrap(), 50).await?;
+        if spotify_track_page.items.is_empty() {
+            // spotify has nothing more to give, the check below reports the shortfall
+            break;
+        }
+        tracks_vector.append(&mut spotify_track_page.items);
 
-        let percentage = (f64::from(i32::try_from(tracks_vector.len()).unwrap()) / spotify_track_response["total"].as_f64().unwrap()) * 100.0;
+        let percentage = (tracks_vector.len() as f64 / spotify_track_page.total as f64) * 100.0;
         print!("\rProcessing {:.0}%", percentage);
         std::io::stdout().flush().unwrap();
     }
+    check_complete("tracks", tracks_vector.len(), spotify_track_page.total)?;
 
     // save tracks as json struct to file
-    fs::write(format!("output/tracks_{}.json", Local::now().format("%Y%m%d")), serde_json::to_string(&TracksJson{tracks: tracks_vector}).unwrap()).unwrap();
+    export_run.write_category("tracks", tracks_vector.len(), Some(spotify_track_page.total), &TracksJson{tracks: tracks_vector}).map_err(SpotifyError::Export)?;
 
     print!("\rProcessing 100%\n");
     std::io::stdout().flush().unwrap();
+
+    Ok(())
 }
 
-async fn export_saved_albums(spotify_client: &mut SpotifyClient) {
+/// Exports the saved albums, optionally completing the track lists Spotify truncated
+///
+/// # Arguments
+///
+/// * `spotify_client` - The client to retrieve the albums with
+/// * `export_run` - The export to write the albums to
+/// * `complete_albums` - Whether to retrieve the tracks missing from the track list of an album
+async fn export_saved_albums(spotify_client: &mut SpotifyClient, export_run: &mut ExportRun, complete_albums: bool) -> Result<(), SpotifyError> {
     println!("Exporting saved albums");
     print!("\rProcessing 0%");
     std::io::stdout().flush().unwrap();
 
     // retrieve first 50 albums
-    let mut albums_vector: Vec<Value> = Vec::new();
-    let mut spotify_album_response: Value = spotify_client.get_saved_albums(0, 50).await.unwrap();
-    albums_vector.append(&mut spotify_album_response["items"].as_array().unwrap().clone());
+    let mut spotify_album_page: Page = spotify_client.get_saved_albums(0, 50).await?;
+    let mut albums_vector: Vec<Value> = std::mem::take(&mut spotify_album_page.items);
 
     // keep retrieving albums until our count = total in spotify response
-    while albums_vector.len() < usize::try_from(spotify_album_response["total"].as_i64().unwrap()).unwrap() {
-        spotify_album_response = spotify_client.get_saved_albums(albums_vector.len().try_into().unwrap(), 50).await.unwrap();
-        albums_vector.append(&mut spotify_album_response["items"].as_array().unwrap().clone());
+    while albums_vector.len() < spotify_album_page.total {
+        spotify_album_page = spotify_client.get_saved_albums(albums_vector.len().try_into().unwrap(), 50).await?;
+        if spotify_album_page.items.is_empty() {
+            // spotify has nothing more to give, the check below reports the shortfall
+            break;
+        }
+        albums_vector.append(&mut spotify_album_page.items);
 
-        let percentage = (f64::from(i32::try_from(albums_vector.len()).unwrap()) / spotify_album_response["total"].as_f64().unwrap()) * 100.0;
+        let percentage = (albums_vector.len() as f64 / spotify_album_page.total as f64) * 100.0;
         print!("\rProcessing {:.0}%", percentage);
         std::io::stdout().flush().unwrap();
     }
+    check_complete("albums", albums_vector.len(), spotify_album_page.total)?;
+
+    if complete_albums && !albums_vector.is_empty() {
+        print!("\rProcessing 100%\n");
+        println!("Completing album track lists");
+        let album_count: usize = albums_vector.len();
+        for (album_index, item) in albums_vector.iter_mut().enumerate() {
+            complete_album_tracks(spotify_client, export_run, &mut item["album"]).await?;
+
+            let percentage = ((album_index + 1) as f64 / album_count as f64) * 100.0;
+            print!("\rProcessing {:.0}%", percentage);
+            std::io::stdout().flush().unwrap();
+        }
+    }
 
     // save albums as json struct to file
-    fs::write(format!("output/albums_{}.json", Local::now().format("%Y%m%d")), serde_json::to_string(&AlbumJson{albums: albums_vector}).unwrap()).unwrap();
+    export_run.write_category("albums", albums_vector.len(), Some(spotify_album_page.total), &AlbumJson{albums: albums_vector}).map_err(SpotifyError::Export)?;
 
     print!("\rProcessing 100%\n");
     std::io::stdout().flush().unwrap();
+
+    Ok(())
+}
+
+/// Retrieves the tracks of an album beyond those Spotify includes with the album, the album keeps its tracks as one complete page
+///
+/// # Arguments
+///
+/// * `spotify_client` - The client to retrieve the tracks with
+/// * `export_run` - The export, warned about albums whose tracks could not all be retrieved
+/// * `album` - The album object as returned by Spotify
+async fn complete_album_tracks(spotify_client: &mut SpotifyClient, export_run: &mut ExportRun, album: &mut Value) -> Result<(), SpotifyError> {
+    let total: usize = album["tracks"]["total"].as_u64().unwrap_or(0).try_into().unwrap();
+    if album["tracks"]["items"].as_array().is_none_or(|tracks| tracks.len() >= total) {
+        return Ok(());
+    }
+    let album_id: String = album["id"].as_str().ok_or(SpotifyError::InvalidResponse("album without an id".to_string()))?.to_owned();
+    let tracks: &mut Vec<Value> = album["tracks"]["items"].as_array_mut().unwrap();
+
+    // keep retrieving tracks until our count = total in spotify response
+    while tracks.len() < total {
+        let mut spotify_track_page: Page = spotify_client.get_album_tracks(&album_id, tracks.len().try_into().unwrap(), 50).await?;
+        if spotify_track_page.items.is_empty() {
+            break;
+        }
+        tracks.append(&mut spotify_track_page.items);
+    }
+
+    let retrieved: usize = tracks.len();
+    album["tracks"]["limit"] = Value::from(retrieved);
+    album["tracks"]["next"] = Value::Null;
+    if retrieved < total {
+        export_run.warn(format!("Only {} of the {} tracks of album {} could be retrieved", retrieved, total, album["name"].as_str().unwrap_or(&album_id)));
+    }
+
+    Ok(())
 }
 
-async fn export_saved_audiobooks(spotify_client: &mut SpotifyClient) {
+async fn export_saved_audiobooks(spotify_client: &mut SpotifyClient, export_run: &mut ExportRun) -> Result<(), SpotifyError> {
     println!("Exporting saved audiobooks");
     print!("\rProcessing 0%");
     std::io::stdout().flush().unwrap();
 
     // retrieve first 50 audiobooks
-    let mut audiobooks_vector: Vec<Value> = Vec::new();
-    let mut spotify_audiobook_response: Value = spotify_client.get_saved_audiobooks(0, 50).await.unwrap();
-    audiobooks_vector.append(&mut spotify_audiobook_response["items"].as_array().unwrap().clone());
+    let mut spotify_audiobook_page: Page = spotify_client.get_saved_audiobooks(0, 50).await?;
+    let mut audiobooks_vector: Vec<Value> = std::mem::take(&mut spotify_audiobook_page.items);
 
     // keep retrieving audiobooks until our count = total in spotify response
-    while audiobooks_vector.len() < usize::try_from(spotify_audiobook_response["total"].as_i64().unwrap()).unwrap() {
-        spotify_audiobook_response = spotify_client.get_saved_albums(audiobooks_vector.len().try_into().unwrap(), 50).await.unwrap();
-        audiobooks_vector.append(&mut spotify_audiobook_response["items"].as_array().unwrap().clone());
+    while audiobooks_vector.len() < spotify_audiobook_page.total {
+        spotify_audiobook_page = spotify_client.get_saved_audiobooks(audiobooks_vector.len().try_into().unwrap(), 50).await?;
+        if spotify_audiobook_page.items.is_empty() {
+            // spotify has nothing more to give, the check below reports the shortfall
+            break;
+        }
+        audiobooks_vector.append(&mut spotify_audiobook_page.items);
 
-        let percentage = (f64::from(i32::try_from(audiobooks_vector.len()).unwrap()) / spotify_audiobook_response["total"].as_f64().unwrap()) * 100.0;
+        let percentage = (audiobooks_vector.len() as f64 / spotify_audiobook_page.total as f64) * 100.0;
         print!("\rProcessing {:.0}%", percentage);
         std::io::stdout().flush().unwrap();
     }
+    check_complete("audiobooks", audiobooks_vector.len(), spotify_audiobook_page.total)?;
+
+    // the audiobooks come with their authors, narrators and edition but without their chapters
+    if !audiobooks_vector.is_empty() {
+        print!("\rProcessing 100%\n");
+        println!("Exporting chapters of saved audiobooks");
+        let audiobook_count: usize = audiobooks_vector.len();
+        for (audiobook_index, audiobook) in audiobooks_vector.iter_mut().enumerate() {
+            audiobook["chapters"] = Value::Array(export_audiobook_chapters(spotify_client, export_run, audiobook).await?);
+
+            let percentage = ((audiobook_index + 1) as f64 / audiobook_count as f64) * 100.0;
+            print!("\rProcessing {:.0}%", percentage);
+            std::io::stdout().flush().unwrap();
+        }
+    }
 
     // save audiobooks as json struct to file
-    fs::write(format!("output/audiobooks_{}.json", Local::now().format("%Y%m%d")), serde_json::to_string(&AudiobookJson{audiobooks: audiobooks_vector}).unwrap()).unwrap();
+    export_run.write_category("audiobooks", audiobooks_vector.len(), Some(spotify_audiobook_page.total), &AudiobookJson{audiobooks: audiobooks_vector}).map_err(SpotifyError::Export)?;
 
     print!("\rProcessing 100%\n");
     std::io::stdout().flush().unwrap();
+
+    Ok(())
+}
+
+/// Retrieves every chapter of the audiobook, their resume_point tells how far each was listened to and whether it was fully played
+///
+/// # Arguments
+///
+/// * `spotify_client` - The client to retrieve the chapters with
+/// * `export_run` - The export, warned about audiobooks whose chapters could not all be retrieved
+/// * `audiobook` - The audiobook object as returned by Spotify
+async fn export_audiobook_chapters(spotify_client: &mut SpotifyClient, export_run: &mut ExportRun, audiobook: &Value) -> Result<Vec<Value>, SpotifyError> {
+    let audiobook_id: &str = audiobook["id"].as_str().ok_or(SpotifyError::InvalidResponse("audiobook without an id".to_string()))?;
+
+    // retrieve first 50 chapters
+    let mut spotify_chapter_page: Page = spotify_client.get_audiobook_chapters(audiobook_id, 0, 50).await?;
+    let mut chapters_vector: Vec<Value> = std::mem::take(&mut spotify_chapter_page.items);
+
+    // keep retrieving chapters until our count = total in spotify response
+    while chapters_vector.len() < spotify_chapter_page.total {
+        spotify_chapter_page = spotify_client.get_audiobook_chapters(audiobook_id, chapters_vector.len().try_into().unwrap(), 50).await?;
+        if spotify_chapter_page.items.is_empty() {
+            break;
+        }
+        chapters_vector.append(&mut spotify_chapter_page.items);
+    }
+
+    if chapters_vector.len() < spotify_chapter_page.total {
+        export_run.warn(format!("Only {} of the {} chapters of audiobook {} could be retrieved", chapters_vector.len(), spotify_chapter_page.total, audiobook["name"].as_str().unwrap_or(audiobook_id)));
+    }
+
+    Ok(chapters_vector)
 }
 
-async fn export_saved_episodes(spotify_client: &mut SpotifyClient) {
+async fn export_saved_episodes(spotify_client: &mut SpotifyClient, export_run: &mut ExportRun) -> Result<(), SpotifyError> {
     println!("Exporting saved episodes");
     print!("\rProcessing 0%");
     std::io::stdout().flush().unwrap();
 
     // retrieve first 50 episodes
-    let mut episodes_vector: Vec<Value> = Vec::new();
-    let mut spotify_episode_response: Value = spotify_client.get_saved_episodes(0, 50).await.unwrap();
-    episodes_vector.append(&mut spotify_episode_response["items"].as_array().unwrap().clone());
+    let mut spotify_episode_page: Page = spotify_client.get_saved_episodes(0, 50).await?;
+    let mut episodes_vector: Vec<Value> = std::mem::take(&mut spotify_episode_page.items);
 
     // keep retrieving episodes until our count = total in spotify response
-    while episodes_vector.len() < usize::try_from(spotify_episode_response["total"].as_i64().unwrap()).unwrap() {
-        spotify_episode_response = spotify_client.get_saved_albums(episodes_vector.len().try_into().unwrap(), 50).await.unwrap();
-        episodes_vector.append(&mut spotify_episode_response["items"].as_array().unwrap().clone());
+    while episodes_vector.len() < spotify_episode_page.total {
+        spotify_episode_page = spotify_client.get_saved_episodes(episodes_vector.len().try_into().unwrap(), 50).await?;
+        if spotify_episode_page.items.is_empty() {
+            // spotify has nothing more to give, the check below reports the shortfall
+            break;
+        }
+        episodes_vector.append(&mut spotify_episode_page.items);
 
-        let percentage = (f64::from(i32::try_from(episodes_vector.len()).unwrap()) / spotify_episode_response["total"].as_f64().unwrap()) * 100.0;
+        let percentage = (episodes_vector.len() as f64 / spotify_episode_page.total as f64) * 100.0;
         print!("\rProcessing {:.0}%", percentage);
         std::io::stdout().flush().unwrap();
     }
+    check_complete("episodes", episodes_vector.len(), spotify_episode_page.total)?;
 
     // save episodes as json struct to file
-    fs::write(format!("output/episodes_{}.json", Local::now().format("%Y%m%d")), serde_json::to_string(&EpisodeJson{episodes: episodes_vector}).unwrap()).unwrap();
+    export_run.write_category("episodes", episodes_vector.len(), Some(spotify_episode_page.total), &EpisodeJson{episodes: episodes_vector}).map_err(SpotifyError::Export)?;
 
     print!("\rProcessing 100%\n");
     std::io::stdout().flush().unwrap();
+
+    Ok(())
+}
+
+/// Exports the profile of the account the export belongs to
+///
+/// # Arguments
+///
+/// * `export_run` - The export to write the profile to
+/// * `current_user` - The profile as returned by Spotify
+fn export_user_profile(export_run: &mut ExportRun, current_user: &Value) -> Result<(), SpotifyError> {
+    println!("Exporting user profile");
+
+    let profile: Value = json!({
+        "id": current_user["id"],
+        "display_name": current_user["display_name"],
+        "country": current_user["country"],
+        "product": current_user["product"],
+        "followers": current_user["followers"]["total"],
+        "images": current_user["images"]
+    });
+    export_run.write_category("profile", 1, None, &json!({"profile": profile})).map_err(SpotifyError::Export)
 }
 
-async fn export_user_playlists(spotify_client: &mut SpotifyClient) {
+/// Exports the playlists the user owns or follows along with their tracks, marking which are owned
+///
+/// # Arguments
+///
+/// * `spotify_client` - The client to retrieve the playlists with
+/// * `export_run` - The export to write the playlists to
+/// * `user_id` - The id of the user, playlists of other owners are followed
+async fn export_user_playlists(spotify_client: &mut SpotifyClient, export_run: &mut ExportRun, user_id: &str) -> Result<(), SpotifyError> {
     println!("Exporting users owned or followed playlists");
     print!("\rProcessing 0%");
     std::io::stdout().flush().unwrap();
 
     // retrieve first 50 playlists
     let mut playlists_vector: Vec<Value> = Vec::new();
-    let mut spotify_playlist_response: Value = spotify_client.get_owned_followed_playlists(0, 50).await.unwrap();
-    for mut playlist in spotify_playlist_response["items"].as_array().unwrap().to_owned() {
-        let spotify_track_response: Value = spotify_client.get_playlist_tracks(playlist["id"].as_str().unwrap(), 0, 50).await.unwrap();
-        playlist["tracks"] = spotify_track_response["items"].clone();
+    let mut spotify_playlist_page: Page = spotify_client.get_owned_followed_playlists(0, 50).await?;
+    for mut playlist in std::mem::take(&mut spotify_playlist_page.items) {
+        playlist["tracks"] = Value::Array(export_playlist_tracks(spotify_client, export_run, &playlist).await?);
+        playlist["owned"] = Value::Bool(playlist["owner"]["id"].as_str() == Some(user_id));
 
         playlists_vector.push(playlist);
     }
 
     // keep retrieving playlists until our count = total in spotify response
-    while playlists_vector.len() < usize::try_from(spotify_playlist_response["total"].as_i64().unwrap()).unwrap() {
-        spotify_playlist_response = spotify_client.get_owned_followed_playlists(playlists_vector.len().try_into().unwrap(), 50).await.unwrap();
-        for mut playlist in spotify_playlist_response["items"].as_array().unwrap().to_owned() {
-            let spotify_track_response: Value = spotify_client.get_playlist_tracks(playlist["id"].as_str().unwrap(), 0, 50).await.unwrap();
-            playlist["tracks"] = spotify_track_response["items"].clone();
-    
+    while playlists_vector.len() < spotify_playlist_page.total {
+        spotify_playlist_page = spotify_client.get_owned_followed_playlists(playlists_vector.len().try_into().unwrap(), 50).await?;
+        if spotify_playlist_page.items.is_empty() {
+            // spotify has nothing more to give, the check below reports the shortfall
+            break;
+        }
+        for mut playlist in std::mem::take(&mut spotify_playlist_page.items) {
+            playlist["tracks"] = Value::Array(export_playlist_tracks(spotify_client, export_run, &playlist).await?);
+            playlist["owned"] = Value::Bool(playlist["owner"]["id"].as_str() == Some(user_id));
+
             playlists_vector.push(playlist);
         }
 
-        let percentage = (f64::from(i32::try_from(playlists_vector.len()).unwrap()) / spotify_playlist_response["total"].as_f64().unwrap()) * 100.0;
+        let percentage = (playlists_vector.len() as f64 / spotify_playlist_page.total as f64) * 100.0;
         print!("\rProcessing {:.0}%", percentage);
         std::io::stdout().flush().unwrap();
     }
+    check_complete("playlists", playlists_vector.len(), spotify_playlist_page.total)?;
 
-    // save playlists as json struct to file
-    fs::write(format!("output/playlists_{}.json", Local::now().format("%Y%m%d")), serde_json::to_string(&PlaylistJson{playlists: playlists_vector}).unwrap()).unwrap();
-
+    let owned: usize = playlists_vector.iter().filter(|playlist| playlist["owned"] == Value::Bool(true)).count();
     print!("\rProcessing 100%\n");
+    println!("{} owned and {} followed playlists", owned, playlists_vector.len() - owned);
+
+    // save playlists as json struct to file
+    export_run.write_category("playlists", playlists_vector.len(), Some(spotify_playlist_page.total), &PlaylistJson{playlists: playlists_vector}).map_err(SpotifyError::Export)?;
     std::io::stdout().flush().unwrap();
+
+    Ok(())
 }
 
-async fn export_saved_shows(spotify_client: &mut SpotifyClient) {
+/// Retrieves every track of the playlist
+///
+/// # Arguments
+///
+/// * `spotify_client` - The client to retrieve the tracks with
+/// * `export_run` - The export, warned about items that are no longer available
+/// * `playlist` - The playlist object as returned by Spotify
+async fn export_playlist_tracks(spotify_client: &mut SpotifyClient, export_run: &mut ExportRun, playlist: &Value) -> Result<Vec<Value>, SpotifyError> {
+    let playlist_id: &str = playlist["id"].as_str().ok_or(SpotifyError::InvalidResponse("playlist without an id".to_string()))?;
+
+    // retrieve first 50 tracks
+    let mut spotify_track_page: Page = spotify_client.get_playlist_tracks(playlist_id, 0, 50).await?;
+    let mut tracks_vector: Vec<Value> = std::mem::take(&mut spotify_track_page.items);
+
+    // keep retrieving tracks until our count = total in spotify response
+    while tracks_vector.len() < spotify_track_page.total {
+        spotify_track_page = spotify_client.get_playlist_tracks(playlist_id, tracks_vector.len().try_into().unwrap(), 50).await?;
+        if spotify_track_page.items.is_empty() {
+            break;
+        }
+        tracks_vector.append(&mut spotify_track_page.items);
+    }
+    let playlist_name: &str = playlist["name"].as_str().unwrap_or(playlist_id);
+    check_complete(&format!("tracks of playlist {}", playlist_name), tracks_vector.len(), spotify_track_page.total)?;
+
+    // spotify returns items whose track was removed from the catalogue without the track itself
+    let unavailable_tracks: usize = tracks_vector.iter().filter(|item| item["track"].is_null()).count();
+    if unavailable_tracks > 0 {
+        export_run.warn(format!("{} tracks of playlist {} are no longer available on Spotify", unavailable_tracks, playlist_name));
+    }
+
+    Ok(tracks_vector)
+}
+
+/// Exports the saved shows, optionally with their episodes
+///
+/// # Arguments
+///
+/// * `spotify_client` - The client to retrieve the shows with
+/// * `export_run` - The export to write the shows to
+/// * `show_episodes` - Whether to retrieve the episodes of every show, kept under episodes in the show
+async fn export_saved_shows(spotify_client: &mut SpotifyClient, export_run: &mut ExportRun, show_episodes: bool) -> Result<(), SpotifyError> {
     println!("Exporting saved shows");
     print!("\rProcessing 0%");
     std::io::stdout().flush().unwrap();
 
     // retrieve first 50 shows
-    let mut shows_vector: Vec<Value> = Vec::new();
-    let mut spotify_show_response: Value = spotify_client.get_saved_episodes(0, 50).await.unwrap();
-    shows_vector.append(&mut spotify_show_response["items"].as_array().unwrap().clone());
+    let mut spotify_show_page: Page = spotify_client.get_saved_shows(0, 50).await?;
+    let mut shows_vector: Vec<Value> = std::mem::take(&mut spotify_show_page.items);
 
     // keep retrieving shows until our count = total in spotify response
-    while shows_vector.len() < usize::try_from(spotify_show_response["total"].as_i64().unwrap()).unwrap() {
-        spotify_show_response = spotify_client.get_saved_shows(shows_vector.len().try_into().unwrap(), 50).await.unwrap();
-        shows_vector.append(&mut spotify_show_response["items"].as_array().unwrap().clone());
+    while shows_vector.len() < spotify_show_page.total {
+        spotify_show_page = spotify_client.get_saved_shows(shows_vector.len().try_into().unwrap(), 50).await?;
+        if spotify_show_page.items.is_empty() {
+            // spotify has nothing more to give, the check below reports the shortfall
+            break;
+        }
+        shows_vector.append(&mut spotify_show_page.items);
 
-        let percentage = (f64::from(i32::try_from(shows_vector.len()).unwrap()) / spotify_show_response["total"].as_f64().unwrap()) * 100.0;
+        let percentage = (shows_vector.len() as f64 / spotify_show_page.total as f64) * 100.0;
         print!("\rProcessing {:.0}%", percentage);
         std::io::stdout().flush().unwrap();
     }
+    check_complete("shows", shows_vector.len(), spotify_show_page.total)?;
+
+    if show_episodes {
+        print!("\rProcessing 100%\n");
+        println!("Exporting episodes of saved shows");
+        let show_count: usize = shows_vector.len();
+        for (show_index, item) in shows_vector.iter_mut().enumerate() {
+            item["show"]["episodes"] = Value::Array(export_show_episodes(spotify_client, export_run, &item["show"]).await?);
+
+            let percentage = ((show_index + 1) as f64 / show_count as f64) * 100.0;
+            print!("\rProcessing {:.0}%", percentage);
+            std::io::stdout().flush().unwrap();
+        }
+    }
 
     // save shows as json struct to file
-    fs::write(format!("output/shows_{}.json", Local::now().format("%Y%m%d")), serde_json::to_string(&ShowJson{shows: shows_vector}).unwrap()).unwrap();
+    export_run.write_category("shows", shows_vector.len(), Some(spotify_show_page.total), &ShowJson{shows: shows_vector}).map_err(SpotifyError::Export)?;
 
     print!("\rProcessing 100%\n");
     std::io::stdout().flush().unwrap();
+
+    Ok(())
 }
 
-async fn export_followed_artists(spotify_client: &mut SpotifyClient) {
+/// Retrieves every episode of the show, their resume_point tells how far each was listened to and whether it was fully played
+///
+/// # Arguments
+///
+/// * `spotify_client` - The client to retrieve the episodes with
+/// * `export_run` - The export, warned about shows whose episodes could not all be retrieved
+/// * `show` - The show object as returned by Spotify
+async fn export_show_episodes(spotify_client: &mut SpotifyClient, export_run: &mut ExportRun, show: &Value) -> Result<Vec<Value>, SpotifyError> {
+    let show_id: &str = show["id"].as_str().ok_or(SpotifyError::InvalidResponse("show without an id".to_string()))?;
+
+    // retrieve first 50 episodes
+    let mut spotify_episode_page: Page = spotify_client.get_show_episodes(show_id, 0, 50).await?;
+    let mut episodes_vector: Vec<Value> = std::mem::take(&mut spotify_episode_page.items);
+
+    // keep retrieving episodes until our count = total in spotify response
+    while episodes_vector.len() < spotify_episode_page.total {
+        spotify_episode_page = spotify_client.get_show_episodes(show_id, episodes_vector.len().try_into().unwrap(), 50).await?;
+        if spotify_episode_page.items.is_empty() {
+            break;
+        }
+        episodes_vector.append(&mut spotify_episode_page.items);
+    }
+
+    if episodes_vector.len() < spotify_episode_page.total {
+        export_run.warn(format!("Only {} of the {} episodes of show {} could be retrieved", episodes_vector.len(), spotify_episode_page.total, show["name"].as_str().unwrap_or(show_id)));
+    }
+
+    Ok(episodes_vector)
+}
+
+async fn export_followed_artists(spotify_client: &mut SpotifyClient, export_run: &mut ExportRun) -> Result<(), SpotifyError> {
     println!("Exporting followed artists");
     print!("\rProcessing 0%");
     std::io::stdout().flush().unwrap();
 
     // retrieve first 50 artists
-    let mut artists_vector: Vec<Value> = Vec::new();
-    let mut spotify_artist_response: Value = spotify_client.get_followed_artists("", 50).await.unwrap();
-    artists_vector.append(&mut spotify_artist_response["artists"]["items"].as_array().unwrap().clone());
+    let mut spotify_artist_page: Page = spotify_client.get_followed_artists("", 50).await?;
+    let mut artists_vector: Vec<Value> = std::mem::take(&mut spotify_artist_page.items);
 
     // keep retrieving artists until our count = total in spotify response
-    let mut after: String = spotify_artist_response["artists"]["cursors"]["after"].as_str().unwrap().to_owned();
-    while artists_vector.len() < usize::try_from(spotify_artist_response["artists"]["total"].as_i64().unwrap()).unwrap() {
-        spotify_artist_response = spotify_client.get_followed_artists(&after, 50).await.unwrap();
-        artists_vector.append(&mut spotify_artist_response["artists"]["items"].as_array().unwrap().clone());
-        after = spotify_artist_response["artists"]["cursors"]["after"].as_str().unwrap_or_default().to_owned();
+    while artists_vector.len() < spotify_artist_page.total {
+        let Some(after) = spotify_artist_page.after.take() else {
+            // no cursor means spotify has nothing more to give, the check below reports the shortfall
+            break;
+        };
+        spotify_artist_page = spotify_client.get_followed_artists(&after, 50).await?;
+        if spotify_artist_page.items.is_empty() {
+            break;
+        }
+        artists_vector.append(&mut spotify_artist_page.items);
 
-        let percentage = (f64::from(i32::try_from(artists_vector.len()).unwrap()) / spotify_artist_response["artists"]["total"].as_f64().unwrap()) * 100.0;
+        let percentage = (artists_vector.len() as f64 / spotify_artist_page.total as f64) * 100.0;
         print!("\rProcessing {:.0}%", percentage);
         std::io::stdout().flush().unwrap();
     }
+    check_complete("artists", artists_vector.len(), spotify_artist_page.total)?;
 
     // save artists as json struct to file
-    fs::write(format!("output/artists_{}.json", Local::now().format("%Y%m%d")), serde_json::to_string(&ArtistJson{artists: artists_vector}).unwrap()).unwrap();
+    export_run.write_category("artists", artists_vector.len(), Some(spotify_artist_page.total), &ArtistJson{artists: artists_vector}).map_err(SpotifyError::Export)?;
 
     print!("\rProcessing 100%\n");
     std::io::stdout().flush().unwrap();
+
+    Ok(())
 }
 
-fn zip_exported_json() -> zip::result::ZipResult<()> {
-    println!("Zipping exported files");
-    std::io::stdout().flush().unwrap();
+/// Restores the saved items and followed artists of an export into the account of the profile
+///
+/// # Arguments
+///
+/// * `profile` - The profile of the account to restore into
+/// * `path` - The export folder or archive to restore
+/// * `archive_args` - The archive options, giving the passphrase of encrypted archives
+/// * `restore_options` - What to restore
+async fn restore_export(profile: &Profile, path: &Path, archive_args: &ArchiveArgs, restore_options: &RestoreOptions) -> Result<(), SpotifyError> {
+    let mut export_source: ExportSource = archive_args.passphrase().and_then(|passphrase| ExportSource::open(path, passphrase)).map_err(SpotifyError::Export)?;
+    let manifest: Manifest = export_source.read_manifest().map_err(SpotifyError::Export)?;
+
+    // the permissions to change the library are only needed when actually restoring
+    let mut spotify_client: SpotifyClient = profile.create_client()?;
+    if !restore_options.dry_run {
+        spotify_client.request_scope(LIBRARY_WRITE_SCOPE);
+        if restore_options.playlists || restore_options.follow_playlists {
+            spotify_client.request_scope(PLAYLIST_WRITE_SCOPE);
+        }
+    }
+    spotify_client.get_access_token().await?;
+
+    let current_user: Value = spotify_client.get_current_user().await?;
+    let user_id: &str = current_user["id"].as_str().unwrap();
+    println!("Restoring export of {} from {} into account {}", manifest.account_id.as_deref().unwrap_or("an unknown account"), manifest.exported_at, user_id);
+
+    restore::restore_library(&mut spotify_client, &mut export_source, &manifest, restore_options.dry_run).await?;
+    if restore_options.playlists || restore_options.follow_playlists {
+        restore::restore_playlists(&mut spotify_client, &mut export_source, &manifest, user_id, restore_options.playlists, restore_options.follow_playlists, restore_options.dry_run).await?;
+    }
+
+    Ok(())
+}
 
-    let zip_file: File = File::create(format!("output/{}_exported.zip", Local::now().format("%Y%m%d"))).unwrap();
-    let mut zip_writer = zip::ZipWriter::new(zip_file);
+/// Imports a "Download your data" package into the output folder of the profile, reconciling it with the latest export
+///
+/// # Arguments
+///
+/// * `profile` - The profile to import into
+/// * `path` - The package zip or the folder it was extracted to
+/// * `archive_args` - The archive options, giving the passphrase of the latest export if it is an encrypted archive
+fn import_gdpr(profile: &Profile, path: &Path, archive_args: &ArchiveArgs) -> Result<(), String> {
+    println!("Importing {}", path.display());
+
+    let api_export: Option<ExportSource> = match retention::latest_export(profile.output_dir()) {
+        Some(export_path) => {
+            println!("Reconciling with the export {}", export_path.display());
+            Some(ExportSource::open(&export_path, archive_args.passphrase()?)?)
+        }
+        None => None
+    };
 
-    let output_path_dir: PathBuf = PathBuf::from_str("output").unwrap();
-    for entry in output_path_dir.read_dir().unwrap() {
-        match entry {
-            Ok(e) => {
-                if e.path().is_file() && e.path().to_string_lossy().ends_with(&format!("{}.json", Local::now().format("%Y%m%d"))) {
-                    zip_writer.start_file(e.path().file_name().unwrap().to_string_lossy(), SimpleFileOptions::default()).unwrap();
+    let completed_import: CompletedExport = gdpr::import_package(path, &profile.output_dir().join(gdpr::GDPR_DIR), &profile.name, api_export)?;
+    println!("Imported into {}", completed_import.dir.display());
 
-                    let mut to_zip_file: File = File::open(e.path()).unwrap();
-                    std::io::copy(&mut to_zip_file, &mut zip_writer).unwrap();
-                    std::fs::remove_file(e.path()).unwrap();
+    Ok(())
+}
+
+/// Computes and prints a statistics report of the profile
+///
+/// # Arguments
+///
+/// * `profile` - The profile to report on
+/// * `report` - The report asked for
+/// * `archive_args` - The archive options, giving the passphrase of encrypted archives
+fn print_stats(profile: &Profile, report: &StatsReport, archive_args: &ArchiveArgs) -> Result<(), String> {
+    match report {
+        StatsReport::History { top, format } => {
+            let plays: Vec<gdpr::Play> = gdpr::read_streaming_history(&profile.output_dir().join(gdpr::GDPR_DIR))?;
+            let history_report: HistoryReport = stats::history_report(&plays, *top);
+            match format {
+                StatsFormat::Table => history_report.print_table(),
+                StatsFormat::Json => println!("{}", serde_json::to_string_pretty(&history_report).unwrap())
+            }
+        },
+        StatsReport::Library { path, top, format } => {
+            let path: PathBuf = path.clone().or_else(|| retention::latest_export(profile.output_dir()))
+                .ok_or(format!("No export found in {}", profile.output_dir().display()))?;
+            let mut export_source: ExportSource = ExportSource::open(&path, archive_args.passphrase()?)?;
+            let manifest: Manifest = export_source.read_manifest()?;
+            let library_report: LibraryReport = stats::library_report(&mut export_source, &manifest, *top)?;
+            match format {
+                StatsFormat::Table => {
+                    println!("Library of {} exported {}", manifest.account_id.as_deref().unwrap_or("an unknown account"), manifest.exported_at);
+                    library_report.print_table();
                 }
+                StatsFormat::Json => println!("{}", serde_json::to_string_pretty(&library_report).unwrap())
             }
-            Err(_) => todo!(),
         }
     }
 
-    zip_writer.finish().unwrap();
+    Ok(())
+}
+
+/// Verifies an export and reports what is wrong with it, exiting with an error if anything is
+///
+/// # Arguments
+///
+/// * `path` - The export folder or archive to verify
+/// * `archive_args` - The archive options, giving the passphrase of encrypted archives
+fn verify_export(path: &Path, archive_args: &ArchiveArgs) {
+    println!("Verifying {}", path.display());
+
+    let verification: Verification = archive_args.passphrase().and_then(|passphrase| verify::verify_export(path, passphrase)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+
+    if !verification.has_manifest {
+        println!("No {} found, the export predates manifests so there are no checksums or totals to compare", MANIFEST_FILE);
+    }
+    if verification.problems.is_empty() {
+        println!("OK, {} categories verified", verification.categories);
+    } else {
+        for problem in &verification.problems {
+            eprintln!("  {}", problem);
+        }
+        eprintln!("Verification failed with {} problems", verification.problems.len());
+        std::process::exit(1);
+    }
+}
+
+/// Exports the recently played tracks, adding them to the history kept between runs
+/// Spotify only keeps the last 50 plays, so the history only covers what was played since the previous run if runs are frequent enough
+///
+/// # Arguments
+///
+/// * `spotify_client` - The client to retrieve the plays with
+/// * `export_run` - The export to write the history to
+/// * `history_path` - The file the history is kept in between runs
+async fn export_recently_played(spotify_client: &mut SpotifyClient, export_run: &mut ExportRun, history_path: &Path) -> Result<(), SpotifyError> {
+    println!("Exporting recently played tracks");
+    print!("\rProcessing 0%");
+    std::io::stdout().flush().unwrap();
+
+    let mut history: Vec<Value> = match fs::read(history_path) {
+        Ok(contents) => serde_json::from_slice::<RecentlyPlayedJson>(&contents)
+            .map_err(|e| SpotifyError::Export(format!("{} is not a valid recently played history: {}", history_path.display(), e)))?
+            .recently_played,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        // any other failure must not be taken for an empty history, saving would then lose the plays kept so far
+        Err(e) => return Err(SpotifyError::Export(format!("could not read the recently played history {}: {}", history_path.display(), e)))
+    };
+    let history_length: usize = history.len();
+    let mut known_plays: HashSet<String> = history.iter().map(play_key).collect();
+
+    // only ask for plays after the last one in the history, continuing while spotify returns full pages
+    let mut after: i64 = history.iter().filter_map(played_at_millis).max().unwrap_or(0);
+    loop {
+        let plays: Vec<Value> = spotify_client.get_recently_played(after, 50).await?;
+        let full_page: bool = plays.len() == 50;
+        let last_played: Option<i64> = plays.iter().filter_map(played_at_millis).max();
+
+        for play in plays {
+            if known_plays.insert(play_key(&play)) {
+                history.push(play);
+            }
+        }
+
+        match last_played {
+            Some(last_played) if full_page && last_played > after => after = last_played,
+            _ => break
+        }
+    }
+    history.sort_by_key(|play| std::cmp::Reverse(played_at_millis(play)));
+
+    // the history is replaced atomically so a failed write never loses the plays collected so far
+    let temp_path: PathBuf = history_path.with_extension("json.tmp");
+    fs::write(&temp_path, serde_json::to_string(&RecentlyPlayedJson{recently_played: history.clone()}).unwrap())
+        .and_then(|_| fs::rename(&temp_path, history_path))
+        .map_err(|e| SpotifyError::History(format!("{}: {}", history_path.display(), e)))?;
+
+    print!("\rProcessing 100%\n");
+    println!("Added {} plays to the history", history.len() - history_length);
+    std::io::stdout().flush().unwrap();
+
+    export_run.write_category("recently_played", history.len(), None, &RecentlyPlayedJson{recently_played: history}).map_err(SpotifyError::Export)?;
+
+    Ok(())
+}
+
+/// Exports the top artists or tracks of every time range, ranked and dated with the export so successive exports can be compared
+///
+/// # Arguments
+///
+/// * `spotify_client` - The client to retrieve the top items with
+/// * `export_run` - The export to write the top items to
+/// * `item_type` - artists or tracks
+/// * `item_object` - The key each ranked item is stored under, artist or track
+async fn export_top_items(spotify_client: &mut SpotifyClient, export_run: &mut ExportRun, item_type: &str, item_object: &str) -> Result<(), SpotifyError> {
+    println!("Exporting top {}", item_type);
+    print!("\rProcessing 0%");
+    std::io::stdout().flush().unwrap();
+
+    let mut top_items: Vec<Value> = Vec::new();
+    for (range_index, time_range) in TOP_TIME_RANGES.iter().enumerate() {
+        let mut spotify_top_page: Page = spotify_client.get_top_items(item_type, time_range, 0, 50).await?;
+        let mut range_items: Vec<Value> = std::mem::take(&mut spotify_top_page.items);
+
+        // keep retrieving items until our count = total in spotify response
+        while range_items.len() < spotify_top_page.total {
+            spotify_top_page = spotify_client.get_top_items(item_type, time_range, range_items.len().try_into().unwrap(), 50).await?;
+            if spotify_top_page.items.is_empty() {
+                break;
+            }
+            range_items.append(&mut spotify_top_page.items);
+        }
+
+        top_items.extend(range_items.into_iter().enumerate().map(|(rank, item)| json!({
+            "time_range": time_range,
+            "rank": rank + 1,
+            item_object: item
+        })));
+
+        let percentage = ((range_index + 1) as f64 / TOP_TIME_RANGES.len() as f64) * 100.0;
+        print!("\rProcessing {:.0}%", percentage);
+        std::io::stdout().flush().unwrap();
+    }
+
+    let category: String = format!("top_{}", item_type);
+    let mut data: Map<String, Value> = Map::new();
+    data.insert("exported_at".to_string(), Value::String(export_run.exported_at().to_owned()));
+    data.insert(category.clone(), Value::Array(top_items));
+    let items: usize = data[&category].as_array().unwrap().len();
+    // spotify caps how far top items can be paged, so the totals it reports are not necessarily reachable and not recorded
+    export_run.write_category(&category, items, None, &data).map_err(SpotifyError::Export)?;
+
+    print!("\rProcessing 100%\n");
+    std::io::stdout().flush().unwrap();
+
+    Ok(())
+}
+
+/// Exports the full artist object of every artist in the export once, as tracks and albums only hold simplified artists without genres or popularity
+///
+/// # Arguments
+///
+/// * `spotify_client` - The client to retrieve the artists with
+/// * `export_run` - The export to collect the artists of and write the catalog to
+async fn export_artists_catalog(spotify_client: &mut SpotifyClient, export_run: &mut ExportRun) -> Result<(), SpotifyError> {
+    println!("Exporting artists catalog");
+    print!("\rProcessing 0%");
+    std::io::stdout().flush().unwrap();
+
+    let mut artist_ids: Vec<String> = Vec::new();
+    let mut seen_ids: HashSet<String> = HashSet::new();
+    let mut add_artists = |artists: &Value| {
+        for id in artists.as_array().into_iter().flatten().filter_map(|artist| artist["id"].as_str()) {
+            if seen_ids.insert(id.to_owned()) {
+                artist_ids.push(id.to_owned());
+            }
+        }
+    };
+
+    for category in ["tracks", "recently_played", "top_tracks"] {
+        for item in export_run.read_category(category).map_err(SpotifyError::Export)?.as_array().into_iter().flatten() {
+            add_artists(&item["track"]["artists"]);
+            add_artists(&item["track"]["album"]["artists"]);
+        }
+    }
+    for item in export_run.read_category("albums").map_err(SpotifyError::Export)?.as_array().into_iter().flatten() {
+        add_artists(&item["album"]["artists"]);
+        for track in item["album"]["tracks"]["items"].as_array().into_iter().flatten() {
+            add_artists(&track["artists"]);
+        }
+    }
+    for playlist in export_run.read_category("playlists").map_err(SpotifyError::Export)?.as_array().into_iter().flatten() {
+        for item in playlist["tracks"].as_array().into_iter().flatten() {
+            add_artists(&item["track"]["artists"]);
+            add_artists(&item["track"]["album"]["artists"]);
+        }
+    }
+
+    // followed and top artists already are full artist objects
+    let mut known_artists: HashMap<String, Value> = HashMap::new();
+    let followed_artists: Value = export_run.read_category("artists").map_err(SpotifyError::Export)?;
+    let top_artists: Value = export_run.read_category("top_artists").map_err(SpotifyError::Export)?;
+    for artist in followed_artists.as_array().into_iter().flatten().chain(top_artists.as_array().into_iter().flatten().map(|item| &item["artist"])) {
+        if let Some(id) = artist["id"].as_str().filter(|id| seen_ids.contains(*id)) {
+            known_artists.insert(id.to_owned(), artist.clone());
+        }
+    }
+
+    let missing_ids: Vec<&str> = artist_ids.iter().map(String::as_str).filter(|id| !known_artists.contains_key(*id)).collect();
+    let mut retrieved: usize = 0;
+    for batch in missing_ids.chunks(50) {
+        for artist in spotify_client.get_artists(batch).await? {
+            if let Some(id) = artist["id"].as_str() {
+                known_artists.insert(id.to_owned(), artist);
+            }
+        }
+        retrieved += batch.len();
+
+        let percentage = (retrieved as f64 / missing_ids.len() as f64) * 100.0;
+        print!("\rProcessing {:.0}%", percentage);
+        std::io::stdout().flush().unwrap();
+    }
+
+    let catalog: Vec<Value> = artist_ids.iter().filter_map(|id| known_artists.remove(id)).collect();
+    if catalog.len() < artist_ids.len() {
+        export_run.warn(format!("{} artists of the export are not known to Spotify", artist_ids.len() - catalog.len()));
+    }
+    // spotify gives null for artists it no longer knows, the warning above records them rather than a total verify would flag
+    export_run.write_category("artists_catalog", catalog.len(), None, &json!({ "artists_catalog": catalog })).map_err(SpotifyError::Export)?;
+
+    print!("\rProcessing 100%\n");
+    std::io::stdout().flush().unwrap();
+
+    Ok(())
+}
+
+/// Identifies a play, the same track is only played once at any moment
+fn play_key(play: &Value) -> String {
+    format!("{}|{}", play["played_at"].as_str().unwrap_or_default(), play["track"]["id"].as_str().unwrap_or_default())
+}
+
+/// When the track was played, as Unix timestamp in milliseconds
+fn played_at_millis(play: &Value) -> Option<i64> {
+    play["played_at"].as_str().and_then(|played_at| DateTime::parse_from_rfc3339(played_at).ok()).map(|played_at| played_at.timestamp_millis())
+}
+
+/// Checks that the number of items collected matches the total Spotify reported, so an incomplete export is never saved
+///
+/// # Arguments
+///
+/// * `category` - What was collected, used in the error
+/// * `collected` - The number of items collected
+/// * `total` - The total reported by Spotify
+fn check_complete(category: &str, collected: usize, total: usize) -> Result<(), SpotifyError> {
+    if collected != total {
+        return Err(SpotifyError::IncompleteExport { category: category.to_owned(), collected, total });
+    }
+
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,244 @@
+use chrono::{DateTime, Local};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use sha2::{Sha256, Digest};
+
+use std::{fs, path::{Path, PathBuf}};
+
+use crate::archive::OpenArchive;
+
+/// Name of the manifest file within an export
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Describes an export so it can be catalogued and verified later on
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub tool_version: String,
+    pub exported_at: String,
+    pub profile: String,
+    pub account_id: Option<String>,
+    pub market: Option<String>,
+    pub categories: Vec<ManifestCategory>,
+    pub warnings: Vec<String>
+}
+
+/// A single exported category and the file it was written to
+#[derive(Serialize, Deserialize)]
+pub struct ManifestCategory {
+    pub category: String,
+    pub file: String,
+    /// Number of items written to the file
+    pub items: usize,
+    /// Number of items Spotify reported, if the category is paged
+    pub total: Option<usize>,
+    pub bytes: usize,
+    pub sha256: String
+}
+
+/// A completed export, its folder and the files written to it
+pub struct CompletedExport {
+    pub dir: PathBuf,
+    /// Every file of the export, the manifest included
+    pub files: Vec<String>
+}
+
+/// Where the files of an export are read from
+pub enum ExportSource {
+    Dir(PathBuf),
+    Archive(OpenArchive)
+}
+
+/// An export in progress, writing its files to a folder of its own and keeping track of them for the manifest
+pub struct ExportRun {
+    dir: PathBuf,
+    started_at: DateTime<Local>,
+    manifest: Manifest
+}
+
+impl ExportSource {
+
+    /// Opens an export folder or archive
+    pub fn open(path: &Path, passphrase: Option<String>) -> Result<Self, String> {
+        if path.is_dir() {
+            return Ok(Self::Dir(path.to_owned()));
+        }
+
+        OpenArchive::open(path, passphrase).map(Self::Archive)
+    }
+
+    /// Names of all files in the export
+    pub fn file_names(&self) -> Vec<String> {
+        match self {
+            Self::Dir(dir) => fs::read_dir(dir).map(|entries| {
+                entries.filter_map(Result::ok)
+                    .filter(|entry| entry.path().is_file())
+                    .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                    .collect()
+            }).unwrap_or_default(),
+            Self::Archive(archive) => archive.file_names()
+        }
+    }
+
+    /// Reads a file of the export
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the file within the export
+    pub fn read(&mut self, name: &str) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Dir(dir) => fs::read(dir.join(name)).map_err(|e| e.to_string()),
+            Self::Archive(archive) => archive.read(name)
+        }
+    }
+
+    /// Reads the data of a category of the export
+    ///
+    /// # Arguments
+    ///
+    /// * `manifest` - The manifest of the export
+    /// * `category` - The category to read
+    ///
+    /// # Returns
+    /// The data kept under the category name in its file, null if the export does not hold the category
+    pub fn read_category(&mut self, manifest: &Manifest, category: &str) -> Result<Value, String> {
+        let Some(manifest_category) = manifest.categories.iter().find(|manifest_category| manifest_category.category == category) else {
+            return Ok(Value::Null);
+        };
+
+        let mut data: Value = self.read(&manifest_category.file)
+            .and_then(|contents| serde_json::from_slice(&contents).map_err(|e| e.to_string()))
+            .map_err(|e| format!("{}: {}", manifest_category.file, e))?;
+
+        Ok(data[category].take())
+    }
+
+    /// Reads the manifest of the export
+    pub fn read_manifest(&mut self) -> Result<Manifest, String> {
+        let manifest_contents: Vec<u8> = self.read(MANIFEST_FILE).map_err(|e| format!("Could not read {}: {}", MANIFEST_FILE, e))?;
+        serde_json::from_slice(&manifest_contents).map_err(|e| format!("{} is not a valid manifest: {}", MANIFEST_FILE, e))
+    }
+}
+
+impl ExportRun {
+
+    /// Creates the folder for a new export within the output directory, named after the time the export started
+    ///
+    /// # Arguments
+    ///
+    /// * `output_dir` - The output directory of the profile
+    /// * `profile` - The name of the profile being exported
+    pub fn start(output_dir: &Path, profile: &str) -> Result<Self, String> {
+        let started_at: DateTime<Local> = Local::now();
+        Self::start_in(output_dir.join(started_at.format("%Y%m%d_%H%M%S").to_string()), started_at, profile)
+    }
+
+    /// Starts writing an export to the given folder, for imports kept in a folder of their own rather than per run
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The folder to write the files and manifest to
+    /// * `started_at` - When the export started, used to date the files
+    /// * `profile` - The name of the profile being exported
+    pub fn start_in(dir: PathBuf, started_at: DateTime<Local>, profile: &str) -> Result<Self, String> {
+        fs::create_dir_all(&dir).map_err(|e| format!("Could not create {}: {}", dir.display(), e))?;
+
+        Ok(Self {
+            dir,
+            started_at,
+            manifest: Manifest {
+                tool_version: env!("CARGO_PKG_VERSION").to_string(),
+                exported_at: started_at.to_rfc3339(),
+                profile: profile.to_owned(),
+                account_id: None,
+                market: None,
+                categories: Vec::new(),
+                warnings: Vec::new()
+            }
+        })
+    }
+
+    /// Records which account the export belongs to
+    ///
+    /// # Arguments
+    ///
+    /// * `account_id` - The Spotify user id
+    /// * `market` - The country of the account, if Spotify returned it
+    pub fn set_account(&mut self, account_id: String, market: Option<String>) {
+        self.manifest.account_id = Some(account_id);
+        self.manifest.market = market;
+    }
+
+    /// When the export started, as RFC 3339
+    pub fn exported_at(&self) -> &str {
+        &self.manifest.exported_at
+    }
+
+    /// Records a warning about the export, which is printed and kept in the manifest
+    pub fn warn(&mut self, warning: String) {
+        println!("Warning: {}", warning);
+        self.manifest.warnings.push(warning);
+    }
+
+    /// Writes a category to its file, tagged with the account and dated with the start of the export, and records it for the manifest
+    ///
+    /// # Arguments
+    ///
+    /// * `category` - The name of the category, also used as file name prefix
+    /// * `items` - The number of items in the data
+    /// * `total` - The number of items Spotify reported, if the category is paged
+    /// * `data` - The data to save as json
+    pub fn write_category<T: Serialize>(&mut self, category: &str, items: usize, total: Option<usize>, data: &T) -> Result<(), String> {
+        let file: String = match &self.manifest.account_id {
+            Some(account_id) => format!("{}_{}_{}.json", category, file_name_safe(account_id), self.started_at.format("%Y%m%d")),
+            None => format!("{}_{}.json", category, self.started_at.format("%Y%m%d"))
+        };
+        let contents: Vec<u8> = serde_json::to_vec(data).map_err(|e| format!("Could not serialize {}: {}", category, e))?;
+        fs::write(self.dir.join(&file), &contents).map_err(|e| format!("Could not write {}: {}", file, e))?;
+
+        self.manifest.categories.push(ManifestCategory {
+            category: category.to_owned(),
+            file,
+            items,
+            total,
+            bytes: contents.len(),
+            sha256: sha256_hex(&contents)
+        });
+
+        Ok(())
+    }
+
+    /// Reads back the data of a category written earlier in the export
+    ///
+    /// # Returns
+    /// The data kept under the category name in its file, null if the category was not written
+    pub fn read_category(&self, category: &str) -> Result<Value, String> {
+        ExportSource::Dir(self.dir.clone()).read_category(&self.manifest, category)
+    }
+
+    /// Writes the manifest, completing the export
+    ///
+    /// # Returns
+    /// The folder of the export and the files written to it
+    pub fn finish(self) -> Result<CompletedExport, String> {
+        let manifest_contents: String = serde_json::to_string_pretty(&self.manifest).map_err(|e| format!("Could not serialize {}: {}", MANIFEST_FILE, e))?;
+        fs::write(self.dir.join(MANIFEST_FILE), manifest_contents).map_err(|e| format!("Could not write {}: {}", MANIFEST_FILE, e))?;
+
+        let mut files: Vec<String> = self.manifest.categories.into_iter().map(|manifest_category| manifest_category.file).collect();
+        files.push(MANIFEST_FILE.to_string());
+
+        Ok(CompletedExport {
+            dir: self.dir,
+            files
+        })
+    }
+}
+
+/// Replaces the characters of an id that do not belong in a file name, older accounts have user names as id rather than generated ids
+fn file_name_safe(id: &str) -> String {
+    id.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' }).collect()
+}
+
+/// SHA-256 of the contents as lowercase hex
+pub fn sha256_hex(contents: &[u8]) -> String {
+    Sha256::digest(contents).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
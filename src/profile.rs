@@ -0,0 +1,181 @@
+use config::Config;
+use serde::Deserialize;
+
+use std::{collections::BTreeMap, path::{Path, PathBuf}, time::Duration};
+
+use crate::{gdpr::GDPR_DIR, retention::RetentionPolicy, spotify::{error::SpotifyError, spotify_client::SpotifyClient, token_store::{self, EncryptedFileTokenStore, PlainFileTokenStore, TokenKey, TokenStore}}};
+
+/// Name of the profile formed by the top level settings in properties.toml
+pub const DEFAULT_PROFILE: &str = "default";
+/// Environment variable holding the passphrase for the encrypted token store, unless a profile names another
+const TOKEN_PASSPHRASE_ENV: &str = "SPOTIFY_EXPORTER_TOKEN_PASSPHRASE";
+/// Refresh token file written by older versions, migrated into the token store of the default profile when found
+const LEGACY_TOKEN_FILE: &str = "token.txt";
+
+/// Settings of a single profile as they appear in properties.toml
+#[derive(Deserialize)]
+struct ProfileProperties {
+    spotify_client_id: Option<String>,
+    spotify_client_secret: Option<String>,
+    oauth_flow_type: Option<String>,
+    redirect_uri: Option<String>,
+    callback_timeout_secs: Option<u64>,
+    callback_page_path: Option<String>,
+    token_store: Option<String>,
+    token_path: Option<String>,
+    token_key_file: Option<String>,
+    token_passphrase_env: Option<String>,
+    keep_last: Option<usize>,
+    keep_daily: Option<usize>,
+    keep_weekly: Option<usize>,
+    keep_monthly: Option<usize>
+}
+
+/// properties.toml, the top level settings form the default profile and [profiles.<name>] tables any further profiles
+#[derive(Deserialize)]
+struct Properties {
+    #[serde(flatten)]
+    default: ProfileProperties,
+    #[serde(default)]
+    profiles: BTreeMap<String, ProfileProperties>
+}
+
+/// A Spotify account to export, with its own client settings, token and output directory
+pub struct Profile {
+    pub name: String,
+    spotify_client_id: String,
+    spotify_client_secret: Option<String>,
+    redirect_uri: String,
+    callback_timeout: Duration,
+    callback_page_path: Option<String>,
+    token_store: String,
+    token_path: PathBuf,
+    token_key_file: Option<String>,
+    token_passphrase_env: String,
+    output_dir: PathBuf,
+    retention_policy: RetentionPolicy
+}
+
+impl Profile {
+
+    /// Builds the profile from its properties, filling in the defaults
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the profile
+    /// * `profile_properties` - The settings of the profile in properties.toml
+    fn from_properties(name: &str, profile_properties: ProfileProperties) -> Result<Self, String> {
+        let spotify_client_id: String = profile_properties.spotify_client_id.ok_or(format!("Profile {} is missing spotify_client_id", name))?;
+
+        // Implicit Grant has been deprecated by Spotify, configs still asking for it are moved over to PKCE
+        if profile_properties.oauth_flow_type.is_some_and(|flow_type| flow_type == "token") {
+            eprintln!("Warning: oauth_flow_type = \"token\"(Implicit Grant) in profile {} is deprecated by Spotify and no longer supported, Authorization Code with PKCE is used instead. \
+                       Remove oauth_flow_type from properties.toml to hide this warning.", name);
+        }
+
+        // the default profile keeps the locations used before profiles existed, other profiles get a folder of their own
+        let token_store: String = profile_properties.token_store.unwrap_or("file".to_string());
+        let token_file_name: &str = if token_store == "encrypted" { "token.enc" } else { "token.json" };
+        let (default_token_path, output_dir) = if name == DEFAULT_PROFILE {
+            (token_store::default_token_dir().join(token_file_name), PathBuf::from("output"))
+        } else {
+            (token_store::default_token_dir().join(name).join(token_file_name), Path::new("output").join(name))
+        };
+
+        Ok(Self {
+            name: name.to_owned(),
+            spotify_client_id,
+            spotify_client_secret: profile_properties.spotify_client_secret.filter(|secret| !secret.is_empty()),
+            redirect_uri: profile_properties.redirect_uri.unwrap_or("http://localhost:8000/callback".to_string()),
+            callback_timeout: Duration::from_secs(profile_properties.callback_timeout_secs.unwrap_or(300)),
+            callback_page_path: profile_properties.callback_page_path,
+            token_store,
+            token_path: profile_properties.token_path.map(PathBuf::from).unwrap_or(default_token_path),
+            token_key_file: profile_properties.token_key_file,
+            token_passphrase_env: profile_properties.token_passphrase_env.unwrap_or(TOKEN_PASSPHRASE_ENV.to_string()),
+            output_dir,
+            retention_policy: RetentionPolicy {
+                keep_last: profile_properties.keep_last.unwrap_or_default(),
+                keep_daily: profile_properties.keep_daily.unwrap_or_default(),
+                keep_weekly: profile_properties.keep_weekly.unwrap_or_default(),
+                keep_monthly: profile_properties.keep_monthly.unwrap_or_default()
+            }
+        })
+    }
+
+    /// Directory the exports of this profile are written to
+    pub fn output_dir(&self) -> &Path {
+        &self.output_dir
+    }
+
+    /// Which exports of this profile to keep
+    pub fn retention_policy(&self) -> &RetentionPolicy {
+        &self.retention_policy
+    }
+
+    /// Creates a Spotify client authorizing with the settings and token store of this profile
+    pub fn create_client(&self) -> Result<SpotifyClient, SpotifyError> {
+        let token_store: Box<dyn TokenStore> = self.create_token_store()?;
+        if self.name == DEFAULT_PROFILE {
+            token_store::migrate_legacy_token_file(token_store.as_ref(), Path::new(LEGACY_TOKEN_FILE))?;
+        }
+
+        Ok(SpotifyClient::new(self.spotify_client_id.clone(), self.spotify_client_secret.clone(), self.redirect_uri.clone(), self.callback_timeout, self.callback_page_path.clone(), token_store))
+    }
+
+    /// Creates the token store configured by token_store, token_path and token_key_file
+    fn create_token_store(&self) -> Result<Box<dyn TokenStore>, SpotifyError> {
+        match self.token_store.as_str() {
+            "file" => Ok(Box::new(PlainFileTokenStore::new(self.token_path.clone()))),
+            "encrypted" => {
+                let token_key: TokenKey = match (&self.token_key_file, std::env::var(&self.token_passphrase_env)) {
+                    (Some(token_key_file), _) => TokenKey::KeyFile(PathBuf::from(token_key_file)),
+                    (_, Ok(passphrase)) if !passphrase.is_empty() => TokenKey::Passphrase(passphrase),
+                    _ => return Err(SpotifyError::TokenStore(format!("token_store = \"encrypted\" needs either token_key_file or the {} environment variable to be set", self.token_passphrase_env)))
+                };
+
+                Ok(Box::new(EncryptedFileTokenStore::new(self.token_path.clone(), token_key)))
+            }
+            token_store => Err(SpotifyError::TokenStore(format!("Unknown token_store {}, expected file or encrypted", token_store)))
+        }
+    }
+}
+
+/// Checks a profile name can be used as folder name within the output and token folders
+///
+/// # Arguments
+///
+/// * `name` - The name of the profile
+fn check_profile_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!("Profile name {} may only hold letters, digits, - and _, please rename it in properties.toml", name));
+    }
+    // the default profile keeps its data package import in output/gdpr, case insensitive file systems included
+    if name.eq_ignore_ascii_case(GDPR_DIR) {
+        return Err(format!("{} is reserved for imported data packages, please rename [profiles.{}]", name, name));
+    }
+
+    Ok(())
+}
+
+/// Loads all profiles from properties.toml
+/// The default profile is only included when spotify_client_id is set at the top level
+pub fn load_profiles() -> Result<Vec<Profile>, String> {
+    let properties: Properties = Config::builder().add_source(config::File::with_name("properties")).build()
+        .and_then(|config| config.try_deserialize())
+        .map_err(|e| format!("Could not load properties.toml: {}", e))?;
+
+    let mut profiles: Vec<Profile> = Vec::new();
+    if properties.default.spotify_client_id.is_some() {
+        profiles.push(Profile::from_properties(DEFAULT_PROFILE, properties.default)?);
+    }
+    for (name, profile_properties) in properties.profiles {
+        if name == DEFAULT_PROFILE {
+            return Err(format!("{} is reserved for the top level settings, please rename [profiles.{}]", DEFAULT_PROFILE, DEFAULT_PROFILE));
+        }
+        check_profile_name(&name)?;
+        profiles.push(Profile::from_properties(&name, profile_properties)?);
+    }
+
+    Ok(profiles)
+}
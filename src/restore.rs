@@ -0,0 +1,260 @@
+use serde_json::{json, Value};
+
+use std::io::Write;
+
+use crate::{manifest::{ExportSource, Manifest, ManifestCategory}, spotify::{error::SpotifyError, paging::Page, spotify_client::SpotifyClient}};
+
+/// Where the items of a category are restored to
+enum Library {
+    /// The saved items of the library, by the name of the library endpoint
+    Saved(&'static str),
+    FollowedArtists
+}
+
+/// A category of the export that can be restored
+struct RestoreCategory {
+    category: &'static str,
+    /// The key of the saved object within an item, none if the items are the objects themselves
+    item_object: Option<&'static str>,
+    library: Library,
+    /// Most ids Spotify accepts in a single request
+    batch_size: usize
+}
+
+const RESTORE_CATEGORIES: [RestoreCategory; 6] = [
+    RestoreCategory { category: "tracks", item_object: Some("track"), library: Library::Saved("tracks"), batch_size: 50 },
+    RestoreCategory { category: "albums", item_object: Some("album"), library: Library::Saved("albums"), batch_size: 20 },
+    RestoreCategory { category: "shows", item_object: Some("show"), library: Library::Saved("shows"), batch_size: 50 },
+    RestoreCategory { category: "episodes", item_object: Some("episode"), library: Library::Saved("episodes"), batch_size: 50 },
+    RestoreCategory { category: "audiobooks", item_object: None, library: Library::Saved("audiobooks"), batch_size: 50 },
+    RestoreCategory { category: "artists", item_object: None, library: Library::FollowedArtists, batch_size: 50 }
+];
+
+/// Saves the tracks, albums, shows, episodes and audiobooks of the export to the library again and follows its artists again
+/// Items that are already in the library are skipped
+///
+/// # Arguments
+///
+/// * `spotify_client` - The client of the account to restore into
+/// * `export_source` - The export to restore
+/// * `manifest` - The manifest of the export
+/// * `dry_run` - Only report what would be restored
+pub async fn restore_library(spotify_client: &mut SpotifyClient, export_source: &mut ExportSource, manifest: &Manifest, dry_run: bool) -> Result<(), SpotifyError> {
+    for restore_category in &RESTORE_CATEGORIES {
+        let Some(manifest_category) = manifest.categories.iter().find(|manifest_category| manifest_category.category == restore_category.category) else {
+            continue;
+        };
+
+        println!("Restoring {}", restore_category.category);
+        let data: Value = read_category(export_source, manifest_category)?;
+        let items: &Vec<Value> = data[restore_category.category].as_array().unwrap();
+
+        // saved items are exported newest first, restoring the oldest first keeps their order in the library
+        let ids: Vec<&str> = items.iter().rev()
+            .filter_map(|item| restore_category.item_object.map_or(item, |item_object| &item[item_object])["id"].as_str())
+            .collect();
+
+        let mut missing_ids: Vec<&str> = Vec::new();
+        for batch in ids.chunks(restore_category.batch_size) {
+            let present: Vec<bool> = match restore_category.library {
+                Library::Saved(library) => spotify_client.check_saved(library, batch).await?,
+                Library::FollowedArtists => spotify_client.check_followed_artists(batch).await?
+            };
+            missing_ids.extend(batch.iter().zip(present).filter(|(_, present)| !present).map(|(id, _)| *id));
+        }
+
+        if !dry_run && !missing_ids.is_empty() {
+            print!("\rProcessing 0%");
+            std::io::stdout().flush().unwrap();
+
+            let mut restored: usize = 0;
+            for batch in missing_ids.chunks(restore_category.batch_size) {
+                match restore_category.library {
+                    Library::Saved(library) => spotify_client.save_to_library(library, batch).await?,
+                    Library::FollowedArtists => spotify_client.follow_artists(batch).await?
+                }
+                restored += batch.len();
+
+                let percentage = (restored as f64 / missing_ids.len() as f64) * 100.0;
+                print!("\rProcessing {:.0}%", percentage);
+                std::io::stdout().flush().unwrap();
+            }
+            println!();
+        }
+
+        let action: &str = if dry_run { "to restore" } else { "restored" };
+        println!("{} {}, {} already present", missing_ids.len(), action, ids.len() - missing_ids.len());
+        if ids.len() < items.len() {
+            // local files and items removed from Spotify have no id to restore them by
+            println!("{} without a Spotify id skipped", items.len() - ids.len());
+        }
+    }
+
+    Ok(())
+}
+
+/// Recreates the playlists of the export owned by the exported account, with their items in order, and follows the playlists of others
+/// Playlists that are already in the library are skipped, as are playlist items that cannot be added, which are reported at the end
+///
+/// # Arguments
+///
+/// * `spotify_client` - The client of the account to restore into
+/// * `export_source` - The export to restore
+/// * `manifest` - The manifest of the export
+/// * `user_id` - The id of the account to restore into
+/// * `recreate_owned` - Whether to recreate the playlists owned by the exported account
+/// * `follow_others` - Whether to follow the playlists owned by others
+/// * `dry_run` - Only report what would be restored
+pub async fn restore_playlists(spotify_client: &mut SpotifyClient, export_source: &mut ExportSource, manifest: &Manifest, user_id: &str, recreate_owned: bool, follow_others: bool, dry_run: bool) -> Result<(), SpotifyError> {
+    let Some(manifest_category) = manifest.categories.iter().find(|manifest_category| manifest_category.category == "playlists") else {
+        println!("The export holds no playlists");
+        return Ok(());
+    };
+
+    println!("Restoring playlists");
+    let data: Value = read_category(export_source, manifest_category)?;
+    let current_playlists: Vec<Value> = get_current_playlists(spotify_client).await?;
+    // exports of older versions do not record the account, the playlists are then taken to be owned by the account restored into
+    let exported_account: &str = manifest.account_id.as_deref().unwrap_or(user_id);
+
+    let mut items_not_added: Vec<String> = Vec::new();
+    for playlist in data["playlists"].as_array().unwrap() {
+        let name: &str = playlist["name"].as_str().unwrap_or_default();
+        let owner: &str = playlist["owner"]["id"].as_str().unwrap_or_default();
+        // exports mark the playlists the account owned, older ones are judged by their owner
+        let owned: bool = playlist["owned"].as_bool().unwrap_or(owner == exported_account);
+
+        if !owned {
+            if !follow_others {
+                continue;
+            }
+            if current_playlists.iter().any(|current_playlist| current_playlist["id"] == playlist["id"]) {
+                println!("Already following playlist {} of {}", name, owner);
+            } else if dry_run {
+                println!("Would follow playlist {} of {}", name, owner);
+            } else {
+                spotify_client.follow_playlist(playlist["id"].as_str().unwrap()).await?;
+                println!("Followed playlist {} of {}", name, owner);
+            }
+            continue;
+        }
+        if !recreate_owned {
+            continue;
+        }
+
+        if current_playlists.iter().any(|current_playlist| current_playlist["owner"]["id"].as_str() == Some(user_id) && current_playlist["name"].as_str() == Some(name)) {
+            println!("Playlist {} already exists, skipped", name);
+            continue;
+        }
+
+        let mut uris: Vec<String> = Vec::new();
+        for item in playlist["tracks"].as_array().map(Vec::as_slice).unwrap_or_default() {
+            match playlist_item_uri(item) {
+                Ok(uri) => uris.push(uri),
+                Err(reason) => items_not_added.push(format!("{}: {}({})", name, item["track"]["name"].as_str().unwrap_or("unknown item"), reason))
+            }
+        }
+
+        if dry_run {
+            println!("Would recreate playlist {} with {} items", name, uris.len());
+            continue;
+        }
+
+        // spotify only allows collaborative playlists to be private
+        let public: bool = playlist["public"].as_bool().unwrap_or(false);
+        let mut playlist_settings: Value = json!({
+            "name": name,
+            "public": public,
+            "collaborative": playlist["collaborative"].as_bool().unwrap_or(false) && !public
+        });
+        // descriptions come back html escaped, they are sent as plain text
+        if let Some(description) = playlist["description"].as_str().filter(|description| !description.is_empty()) {
+            playlist_settings["description"] = Value::String(unescape_html(description));
+        }
+
+        // a failed playlist or batch is reported with the items that could not be added, the restore carries on with the others
+        let playlist_id: String = match spotify_client.create_playlist(user_id, &playlist_settings).await {
+            Ok(playlist_id) => playlist_id,
+            Err(e) => {
+                println!("Could not recreate playlist {}: {}", name, e);
+                items_not_added.extend(uris.iter().map(|uri| format!("{}: {}(playlist could not be created: {})", name, uri, e)));
+                continue;
+            }
+        };
+        let mut added: usize = 0;
+        for batch in uris.chunks(100) {
+            let batch: Vec<&str> = batch.iter().map(String::as_str).collect();
+            match spotify_client.add_playlist_items(&playlist_id, &batch).await {
+                Ok(()) => added += batch.len(),
+                Err(e) => items_not_added.extend(batch.iter().map(|uri| format!("{}: {}({})", name, uri, e)))
+            }
+        }
+        println!("Recreated playlist {} with {} of {} items", name, added, uris.len());
+    }
+
+    if !items_not_added.is_empty() {
+        println!("{} playlist items could not be added :", items_not_added.len());
+        for item_not_added in &items_not_added {
+            println!("  {}", item_not_added);
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads and parses a category file of the export, checking it holds the list of items of the category
+///
+/// # Arguments
+///
+/// * `export_source` - The export to read from
+/// * `manifest_category` - The manifest entry of the category
+fn read_category(export_source: &mut ExportSource, manifest_category: &ManifestCategory) -> Result<Value, SpotifyError> {
+    let data: Value = export_source.read(&manifest_category.file)
+        .and_then(|contents| serde_json::from_slice(&contents).map_err(|e| e.to_string()))
+        .map_err(|e| SpotifyError::Export(format!("{}: {}", manifest_category.file, e)))?;
+    if !data[&manifest_category.category].is_array() {
+        return Err(SpotifyError::Export(format!("{}: expected a {} list", manifest_category.file, manifest_category.category)));
+    }
+
+    Ok(data)
+}
+
+/// Retrieves the playlists the user currently owns or follows
+async fn get_current_playlists(spotify_client: &mut SpotifyClient) -> Result<Vec<Value>, SpotifyError> {
+    let mut playlist_page: Page = spotify_client.get_owned_followed_playlists(0, 50).await?;
+    let mut playlists: Vec<Value> = std::mem::take(&mut playlist_page.items);
+
+    while playlists.len() < playlist_page.total {
+        playlist_page = spotify_client.get_owned_followed_playlists(playlists.len().try_into().unwrap(), 50).await?;
+        if playlist_page.items.is_empty() {
+            break;
+        }
+        playlists.append(&mut playlist_page.items);
+    }
+
+    Ok(playlists)
+}
+
+/// Gives the URI to add a playlist item by
+///
+/// # Returns
+/// The URI, or why the item cannot be added
+fn playlist_item_uri(item: &Value) -> Result<String, &'static str> {
+    if item["is_local"].as_bool().unwrap_or(false) {
+        return Err("local file");
+    }
+    if item["track"].is_null() {
+        return Err("no longer available");
+    }
+
+    // exports of older versions only hold the id, their playlist items were all taken to be tracks
+    match (item["track"]["uri"].as_str(), item["track"]["id"].as_str()) {
+        (Some(uri), _) if !uri.starts_with("spotify:local:") => Ok(uri.to_owned()),
+        (None, Some(id)) => Ok(format!("spotify:track:{}", id)),
+        _ => Err("local file")
+    }
+}
+
+fn unescape_html(text: &str) -> String {
+    text.replace("&quot;", "\"").replace("&#x27;", "'").replace("&#39;", "'").replace("&#x2F;", "/").replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&")
+}
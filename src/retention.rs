@@ -0,0 +1,258 @@
+use chrono::{Datelike, NaiveDate, NaiveDateTime};
+
+use std::{collections::{BTreeMap, HashSet}, fs, path::{Path, PathBuf}};
+
+use crate::manifest::MANIFEST_FILE;
+
+/// How many exports to keep, exports kept by any of the rules are not removed
+pub struct RetentionPolicy {
+    /// The most recent exports
+    pub keep_last: usize,
+    /// The most recent export of each of the last days that have exports
+    pub keep_daily: usize,
+    /// The most recent export of each of the last weeks that have exports
+    pub keep_weekly: usize,
+    /// The most recent export of each of the last months that have exports
+    pub keep_monthly: usize
+}
+
+/// An export found in the output directory, as folder, archive or both
+struct StoredExport {
+    started_at: NaiveDateTime,
+    paths: Vec<PathBuf>,
+    /// Written by an older version, without a manifest and dated by day only
+    legacy: bool,
+    /// The rules the export is kept by, empty if it is to be removed
+    kept_by: Vec<&'static str>
+}
+
+impl RetentionPolicy {
+
+    /// Whether any rule is set, without rules every export is kept
+    pub fn is_enabled(&self) -> bool {
+        self.keep_last > 0 || self.keep_daily > 0 || self.keep_weekly > 0 || self.keep_monthly > 0
+    }
+
+    /// Marks which exports are kept by which rules
+    ///
+    /// # Arguments
+    ///
+    /// * `exports` - The exports, newest first
+    fn apply(&self, exports: &mut [StoredExport]) {
+        for stored_export in exports.iter_mut().take(self.keep_last) {
+            stored_export.kept_by.push("last");
+        }
+
+        keep_newest_per_period(exports, self.keep_daily, "daily", |started_at| started_at.format("%Y%m%d").to_string());
+        keep_newest_per_period(exports, self.keep_weekly, "weekly", |started_at| format!("{}W{}", started_at.iso_week().year(), started_at.iso_week().week()));
+        keep_newest_per_period(exports, self.keep_monthly, "monthly", |started_at| started_at.format("%Y%m").to_string());
+    }
+}
+
+/// Keeps the newest export of each period, for as many periods as asked
+///
+/// # Arguments
+///
+/// * `exports` - The exports, newest first
+/// * `periods` - How many periods to keep an export of
+/// * `rule` - The name of the rule, recorded on the kept exports
+/// * `period_of` - Gives the period an export falls in
+fn keep_newest_per_period(exports: &mut [StoredExport], periods: usize, rule: &'static str, period_of: impl Fn(&NaiveDateTime) -> String) {
+    let mut seen_periods: HashSet<String> = HashSet::new();
+    for stored_export in exports.iter_mut() {
+        if seen_periods.len() == periods {
+            break;
+        }
+        if seen_periods.insert(period_of(&stored_export.started_at)) {
+            stored_export.kept_by.push(rule);
+        }
+    }
+}
+
+/// Finds the exports in the output directory, export folders without a manifest are incomplete and left out
+///
+/// Older versions wrote their files as <category>_<YYYYMMDD>.json straight into the output directory and archived them as
+/// <YYYYMMDD>_exported.zip, the files and archive of a day are taken as one export started at the beginning of that day
+///
+/// # Arguments
+///
+/// * `output_dir` - The output directory of the profile
+///
+/// # Returns
+/// The exports, newest first
+fn find_exports(output_dir: &Path) -> Vec<StoredExport> {
+    let mut exports: BTreeMap<(NaiveDateTime, bool), Vec<PathBuf>> = BTreeMap::new();
+    let Ok(entries) = fs::read_dir(output_dir) else {
+        return Vec::new();
+    };
+
+    for entry in entries.filter_map(Result::ok) {
+        let path: PathBuf = entry.path();
+        let file_name: String = entry.file_name().to_string_lossy().into_owned();
+
+        // exports are named <YYYYMMDD_HHMMSS> for folders and <YYYYMMDD_HHMMSS>_exported.<format> for archives
+        if let Some(started_at) = file_name.get(..15).and_then(|timestamp| NaiveDateTime::parse_from_str(timestamp, "%Y%m%d_%H%M%S").ok()) {
+            let is_export: bool = if path.is_dir() {
+                file_name.len() == 15 && path.join(MANIFEST_FILE).is_file()
+            } else {
+                file_name[15..].starts_with("_exported.")
+            };
+            if is_export {
+                exports.entry((started_at, false)).or_default().push(path);
+            }
+        } else if let Some(exported_on) = legacy_export_date(&file_name).filter(|_| path.is_file()) {
+            exports.entry((exported_on.and_hms_opt(0, 0, 0).unwrap(), true)).or_default().push(path);
+        }
+    }
+
+    exports.into_iter().rev().map(|((started_at, legacy), paths)| StoredExport { started_at, paths, legacy, kept_by: Vec::new() }).collect()
+}
+
+/// Gives the day a file of an older version was exported on, from its name <YYYYMMDD>_exported.zip or <category>_<YYYYMMDD>.json
+fn legacy_export_date(file_name: &str) -> Option<NaiveDate> {
+    let date: &str = match file_name.strip_suffix("_exported.zip") {
+        Some(date) => date,
+        None => file_name.strip_suffix(".json")?.rsplit_once('_')?.1
+    };
+    if date.len() != 8 || !date.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    NaiveDate::parse_from_str(date, "%Y%m%d").ok()
+}
+
+/// Finds the most recent export in the output directory, preferring its folder over its archive
+///
+/// # Arguments
+///
+/// * `output_dir` - The output directory of the profile
+pub fn latest_export(output_dir: &Path) -> Option<PathBuf> {
+    // exports of older versions have no manifest to read them by
+    let stored_export: StoredExport = find_exports(output_dir).into_iter().find(|stored_export| !stored_export.legacy)?;
+    stored_export.paths.iter().find(|path| path.is_dir()).or(stored_export.paths.first()).cloned()
+}
+
+/// Applies the retention policy to the exports in the output directory
+///
+/// # Arguments
+///
+/// * `output_dir` - The output directory of the profile
+/// * `retention_policy` - The rules of which exports to keep
+/// * `dry_run` - Only list what would be kept and removed
+pub fn prune_exports(output_dir: &Path, retention_policy: &RetentionPolicy, dry_run: bool) -> Result<(), String> {
+    if !retention_policy.is_enabled() {
+        println!("No retention configured, keeping all exports");
+        return Ok(());
+    }
+
+    let mut exports: Vec<StoredExport> = find_exports(output_dir);
+    retention_policy.apply(&mut exports);
+
+    for stored_export in &exports {
+        for path in &stored_export.paths {
+            if !stored_export.kept_by.is_empty() {
+                if dry_run {
+                    println!("Would keep {} ({})", path.display(), stored_export.kept_by.join(", "));
+                }
+            } else if dry_run {
+                println!("Would remove {}", path.display());
+            } else {
+                println!("Removing {}", path.display());
+                let removed = if path.is_dir() { fs::remove_dir_all(path) } else { fs::remove_file(path) };
+                removed.map_err(|e| format!("Could not remove {}: {}", path.display(), e))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored_export(started_at: &str) -> StoredExport {
+        StoredExport {
+            started_at: NaiveDateTime::parse_from_str(started_at, "%Y%m%d_%H%M%S").unwrap(),
+            paths: vec![PathBuf::from(started_at)],
+            legacy: false,
+            kept_by: Vec::new()
+        }
+    }
+
+    fn retention_policy(keep_last: usize, keep_daily: usize, keep_weekly: usize, keep_monthly: usize) -> RetentionPolicy {
+        RetentionPolicy { keep_last, keep_daily, keep_weekly, keep_monthly }
+    }
+
+    fn kept_by(exports: &[StoredExport]) -> Vec<Vec<&'static str>> {
+        exports.iter().map(|stored_export| stored_export.kept_by.clone()).collect()
+    }
+
+    #[test]
+    fn keep_last_overlaps_with_daily() {
+        let mut exports: Vec<StoredExport> = vec![stored_export("20240102_100000"), stored_export("20240101_120000"), stored_export("20240101_080000")];
+        retention_policy(2, 2, 0, 0).apply(&mut exports);
+
+        assert_eq!(kept_by(&exports), vec![vec!["last", "daily"], vec!["last", "daily"], vec![]]);
+    }
+
+    #[test]
+    fn weeks_cross_iso_year_boundary() {
+        // 2020-12-31 and 2021-01-01 both fall in ISO week 53 of 2020, 2021-01-04 starts week 1 of 2021
+        let mut exports: Vec<StoredExport> = vec![stored_export("20210104_120000"), stored_export("20210101_120000"), stored_export("20201231_120000"), stored_export("20201224_120000")];
+        retention_policy(0, 0, 2, 0).apply(&mut exports);
+
+        assert_eq!(kept_by(&exports), vec![vec!["weekly"], vec!["weekly"], vec![], vec![]]);
+    }
+
+    #[test]
+    fn fewer_periods_than_requested() {
+        let mut exports: Vec<StoredExport> = vec![stored_export("20240215_120000"), stored_export("20240201_120000"), stored_export("20240115_120000")];
+        retention_policy(0, 0, 0, 12).apply(&mut exports);
+
+        assert_eq!(kept_by(&exports), vec![vec!["monthly"], vec![], vec!["monthly"]]);
+    }
+
+    #[test]
+    fn folder_and_archive_are_kept_or_removed_together() {
+        let output_dir: PathBuf = std::env::temp_dir().join(format!("spotify_exporter_retention_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&output_dir);
+        for timestamp in ["20240102_120000", "20240101_120000"] {
+            fs::create_dir_all(output_dir.join(timestamp)).unwrap();
+            fs::write(output_dir.join(timestamp).join(MANIFEST_FILE), "{}").unwrap();
+            fs::write(output_dir.join(format!("{}_exported.zip", timestamp)), "").unwrap();
+        }
+
+        let exports: Vec<StoredExport> = find_exports(&output_dir);
+        assert_eq!(exports.len(), 2);
+        assert!(exports.iter().all(|stored_export| stored_export.paths.len() == 2));
+
+        prune_exports(&output_dir, &retention_policy(1, 0, 0, 0), false).unwrap();
+        let newest_kept: bool = output_dir.join("20240102_120000").is_dir() && output_dir.join("20240102_120000_exported.zip").is_file();
+        let oldest_removed: bool = !output_dir.join("20240101_120000").exists() && !output_dir.join("20240101_120000_exported.zip").exists();
+        fs::remove_dir_all(&output_dir).unwrap();
+
+        assert!(newest_kept);
+        assert!(oldest_removed);
+    }
+
+    #[test]
+    fn exports_of_older_versions_are_found_by_day() {
+        let output_dir: PathBuf = std::env::temp_dir().join(format!("spotify_exporter_retention_legacy_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&output_dir);
+        fs::create_dir_all(output_dir.join("20240102_120000")).unwrap();
+        fs::write(output_dir.join("20240102_120000").join(MANIFEST_FILE), "{}").unwrap();
+        for file_name in ["20230101_exported.zip", "tracks_20230101.json", "albums_20230101.json", "tracks_20230102.json", "recently_played_history.json"] {
+            fs::write(output_dir.join(file_name), "").unwrap();
+        }
+
+        let exports: Vec<StoredExport> = find_exports(&output_dir);
+        let latest: Option<PathBuf> = latest_export(&output_dir);
+        fs::remove_dir_all(&output_dir).unwrap();
+
+        let started_at: Vec<String> = exports.iter().map(|stored_export| stored_export.started_at.format("%Y%m%d_%H%M%S").to_string()).collect();
+        assert_eq!(started_at, vec!["20240102_120000", "20230102_000000", "20230101_000000"]);
+        assert_eq!(exports.iter().map(|stored_export| (stored_export.legacy, stored_export.paths.len())).collect::<Vec<(bool, usize)>>(), vec![(false, 1), (true, 1), (true, 3)]);
+        assert_eq!(latest, Some(output_dir.join("20240102_120000")));
+    }
+}
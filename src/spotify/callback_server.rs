@@ -0,0 +1,195 @@
+use reqwest::Url;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}, sync::mpsc, task::JoinHandle, time};
+
+use std::{collections::HashMap, io::ErrorKind, sync::Arc, time::Duration};
+
+use super::error::SpotifyError;
+
+/// Largest request head we accept, anything bigger is not a request we are interested in
+const MAX_REQUEST_HEAD_BYTES: usize = 16 * 1024;
+/// How long a single connection may take to send its request, browsers regularly open connections they never use
+const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Page shown to the user with the outcome of a request, unless a custom page is configured
+pub const DEFAULT_RESULT_PAGE: &str = include_str!("../html/result.html");
+
+/// A parsed request on the redirect URI, which still needs to be responded to
+pub struct CallbackRequest {
+    pub query: HashMap<String, String>,
+    stream: TcpStream,
+    result_page: Arc<String>
+}
+
+/// Minimal HTTP server listening on the redirect URI for the OAuth callbacks
+///
+/// Connections are handled concurrently so that favicon requests, browser prefetches and idle connections do not block the actual callback.
+pub struct CallbackServer {
+    requests: mpsc::Receiver<CallbackRequest>,
+    accept_task: JoinHandle<()>
+}
+
+impl CallbackServer {
+
+    /// Binds the server to the host and port of the redirect URI and starts accepting connections
+    ///
+    /// # Arguments
+    ///
+    /// * `redirect_uri` - The redirect URI registered with Spotify, its path is served as the callback route
+    /// * `result_page` - The html template for pages shown to the user, {{title}} and {{message}} are replaced
+    pub async fn bind(redirect_uri: &str, result_page: String) -> Result<Self, SpotifyError> {
+        let redirect_url: Url = Url::parse(redirect_uri).map_err(|e| SpotifyError::InvalidRedirectUri(format!("{} ({})", redirect_uri, e)))?;
+        if redirect_url.scheme() != "http" {
+            return Err(SpotifyError::InvalidRedirectUri(format!("{} (only http is supported for the local callback)", redirect_uri)));
+        }
+
+        // localhost is bound on the IPv4 loopback explicitly so that it matches what browsers resolve it to
+        let host: &str = match redirect_url.host_str() {
+            Some("localhost") => "127.0.0.1",
+            Some(host) => host,
+            None => return Err(SpotifyError::InvalidRedirectUri(format!("{} (missing host)", redirect_uri)))
+        };
+        let address: String = format!("{}:{}", host, redirect_url.port_or_known_default().unwrap());
+
+        let listener: TcpListener = TcpListener::bind(&address).await.map_err(|e| {
+            if e.kind() == ErrorKind::AddrInUse {
+                SpotifyError::PortInUse(address.clone())
+            } else {
+                SpotifyError::Bind(address.clone(), e)
+            }
+        })?;
+
+        let callback_path: String = redirect_url.path().to_owned();
+        let result_page: Arc<String> = Arc::new(result_page);
+        let (sender, requests) = mpsc::channel(8);
+        let accept_task: JoinHandle<()> = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(handle_connection(stream, callback_path.clone(), result_page.clone(), sender.clone()));
+                    }
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            requests,
+            accept_task
+        })
+    }
+
+    /// Waits for the next request on the redirect URI
+    pub async fn next_request(&mut self) -> Option<CallbackRequest> {
+        self.requests.recv().await
+    }
+}
+
+impl Drop for CallbackServer {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+impl CallbackRequest {
+
+    /// Responds with the given html page
+    ///
+    /// # Arguments
+    ///
+    /// * `status` - The HTTP status line, eg "200 OK"
+    /// * `content` - The html to serve
+    pub async fn respond_html(mut self, status: &str, content: &str) {
+        write_response(&mut self.stream, status, content).await;
+    }
+
+    /// Responds with the success or failure page shown to the user at the end of the OAuth flow
+    ///
+    /// # Arguments
+    ///
+    /// * `error` - The error that caused the authorization to fail, or none if it succeeded
+    pub async fn respond_result(self, error: Option<&SpotifyError>) {
+        let content: String = match error {
+            None => fill_result_page(&self.result_page, "Authorization complete", "You can close this window and return to the terminal."),
+            Some(e) => fill_result_page(&self.result_page, "Authorization failed", &e.to_string())
+        };
+        let status: &str = if error.is_none() { "200 OK" } else { "400 Bad Request" };
+
+        self.respond_html(status, &content).await;
+    }
+}
+
+/// Reads and routes a single request, answering anything that is not the callback from Spotify directly
+async fn handle_connection(mut stream: TcpStream, callback_path: String, result_page: Arc<String>, sender: mpsc::Sender<CallbackRequest>) {
+    let request_head: String = match time::timeout(REQUEST_READ_TIMEOUT, read_request_head(&mut stream)).await {
+        Ok(Some(head)) => head,
+        Ok(None) => return write_response(&mut stream, "400 Bad Request", &fill_result_page(&result_page, "Bad request", "The request could not be read.")).await,
+        Err(_) => return
+    };
+
+    // request line is of the format <method> <target> <version>
+    let mut request_line = request_head.lines().next().unwrap_or_default().split_whitespace();
+    let (method, target) = match (request_line.next(), request_line.next(), request_line.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/") => (method, target),
+        _ => return write_response(&mut stream, "400 Bad Request", &fill_result_page(&result_page, "Bad request", "The request could not be read.")).await
+    };
+    if method != "GET" {
+        return write_response(&mut stream, "405 Method Not Allowed", &fill_result_page(&result_page, "Method not allowed", "Only GET requests are supported.")).await;
+    }
+
+    let url: Url = match Url::parse("http://localhost").and_then(|base| base.join(target)) {
+        Ok(url) => url,
+        Err(_) => return write_response(&mut stream, "400 Bad Request", &fill_result_page(&result_page, "Bad request", "The request could not be read.")).await
+    };
+    if url.path() != callback_path {
+        return write_response(&mut stream, "404 Not Found", &fill_result_page(&result_page, "Not found", "Nothing to see here.")).await;
+    }
+
+    let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+    if let Err(mpsc::error::SendError(mut request)) = sender.send(CallbackRequest { query, stream, result_page: result_page.clone() }).await {
+        // the OAuth flow is no longer waiting on requests
+        write_response(&mut request.stream, "503 Service Unavailable", &fill_result_page(&result_page, "Not available", "Authorization is no longer in progress.")).await;
+    }
+}
+
+/// Reads from the stream until the end of the request head
+///
+/// # Returns
+/// The request head, or none if the connection closed early, the head is too large or is not valid UTF-8
+async fn read_request_head(stream: &mut TcpStream) -> Option<String> {
+    let mut head: Vec<u8> = Vec::new();
+    let mut buffer = [0; 1024];
+
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read: usize = stream.read(&mut buffer).await.ok()?;
+        if read == 0 || head.len() + read > MAX_REQUEST_HEAD_BYTES {
+            return None;
+        }
+        head.extend_from_slice(&buffer[..read]);
+    }
+
+    String::from_utf8(head).ok()
+}
+
+async fn write_response(stream: &mut TcpStream, status: &str, content: &str) {
+    let response: String = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content.len(),
+        content
+    );
+
+    // the browser going away before reading the response is not something we can act on
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.flush().await;
+}
+
+/// Fills in the result page template with the given title and message
+fn fill_result_page(result_page: &str, title: &str, message: &str) -> String {
+    result_page.replace("{{title}}", &escape_html(title)).replace("{{message}}", &escape_html(message))
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
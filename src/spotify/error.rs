@@ -0,0 +1,88 @@
+use std::{fmt, io};
+
+/// Errors that can occur while authorizing with or calling the Spotify API
+#[derive(Debug)]
+pub enum SpotifyError {
+    /// The request to Spotify failed
+    Http(reqwest::Error),
+    /// The configured redirect URI could not be used for the local callback listener
+    InvalidRedirectUri(String),
+    /// The port of the redirect URI is already in use by another process
+    PortInUse(String),
+    /// The callback listener could not be bound for any other reason
+    Bind(String, io::Error),
+    /// The configured callback page could not be read
+    CallbackPage(String, io::Error),
+    /// No callback was received from Spotify within the configured number of seconds
+    CallbackTimeout(u64),
+    /// The user did not grant access, or Spotify returned another error in the callback
+    AuthorizationDenied(String),
+    /// The state returned in the callback does not match the state we sent
+    StateMismatch,
+    /// A required parameter is missing from the callback
+    MissingCallbackParameter(&'static str),
+    /// Spotify rejected the request for an access token
+    TokenRequest(String),
+    /// The token could not be loaded from or saved to the token store
+    TokenStore(String),
+    /// Spotify no longer accepts our tokens, the user has to authorize again
+    ReauthorizationRequired(String),
+    /// Spotify returned an error object instead of the requested data
+    Api { status: u16, message: String },
+    /// Spotify returned a response that does not have the expected shape
+    InvalidResponse(String),
+    /// Fewer or more items were retrieved than Spotify reported in total
+    IncompleteExport { category: String, collected: usize, total: usize },
+    /// The exported files could not be archived
+    Archive(String),
+    /// Old exports could not be removed
+    Retention(String),
+    /// An export could not be read or written
+    Export(String),
+    /// The recently played history kept between runs could not be saved
+    History(String)
+}
+
+impl fmt::Display for SpotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpotifyError::Http(e) => write!(f, "Request to Spotify failed: {}", e),
+            SpotifyError::InvalidRedirectUri(uri) => write!(f, "Invalid redirect_uri in properties.toml: {}", uri),
+            SpotifyError::PortInUse(address) => write!(f, "Could not listen for the Spotify callback on {} as the port is already in use, \
+                                                          stop the other service or choose another port in redirect_uri (and update the redirect URI of your Spotify app to match)", address),
+            SpotifyError::Bind(address, e) => write!(f, "Could not listen for the Spotify callback on {}: {}", address, e),
+            SpotifyError::CallbackPage(path, e) => write!(f, "Could not read callback_page_path {}: {}", path, e),
+            SpotifyError::CallbackTimeout(seconds) => write!(f, "No callback received from Spotify within {} seconds", seconds),
+            SpotifyError::AuthorizationDenied(error) => write!(f, "Spotify did not grant access: {}", error),
+            SpotifyError::StateMismatch => write!(f, "State does not match"),
+            SpotifyError::MissingCallbackParameter(parameter) => write!(f, "Callback from Spotify is missing {}", parameter),
+            SpotifyError::TokenRequest(error) => write!(f, "Spotify rejected the access token request: {}", error),
+            SpotifyError::TokenStore(error) => write!(f, "Token storage failed: {}", error),
+            SpotifyError::ReauthorizationRequired(error) => write!(f, "Spotify no longer accepts the stored token({}), run with --token to authorize again in the browser", error),
+            SpotifyError::Api { status, message } => write!(f, "Spotify returned an error({}): {}", status, message),
+            SpotifyError::InvalidResponse(error) => write!(f, "Unexpected response from Spotify: {}", error),
+            SpotifyError::IncompleteExport { category, collected, total } => write!(f, "Retrieved {} {} but Spotify reports {} in total", collected, category, total),
+            SpotifyError::Archive(error) => write!(f, "Archiving the export failed: {}", error),
+            SpotifyError::Retention(error) => write!(f, "Removing old exports failed: {}", error),
+            SpotifyError::Export(error) => write!(f, "Could not read or write the export: {}", error),
+            SpotifyError::History(error) => write!(f, "Could not save the recently played history: {}", error)
+        }
+    }
+}
+
+impl std::error::Error for SpotifyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SpotifyError::Http(e) => Some(e),
+            SpotifyError::Bind(_, e) => Some(e),
+            SpotifyError::CallbackPage(_, e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<reqwest::Error> for SpotifyError {
+    fn from(e: reqwest::Error) -> Self {
+        SpotifyError::Http(e)
+    }
+}
@@ -1 +1,5 @@
-pub mod spotify_client;
\ No newline at end of file
+pub mod callback_server;
+pub mod error;
+pub mod paging;
+pub mod spotify_client;
+pub mod token_store;
\ No newline at end of file
@@ -0,0 +1,40 @@
+use serde_json::Value;
+
+use super::error::SpotifyError;
+
+/// A page of items from one of the Spotify API paging objects
+pub struct Page {
+    pub items: Vec<Value>,
+    pub total: usize,
+    /// Cursor to request the next page with, only set for cursor based paging
+    pub after: Option<String>
+}
+
+impl Page {
+
+    /// Validates that the response has the shape of a paging object and takes its items
+    ///
+    /// # Arguments
+    ///
+    /// * `response` - The paging object returned by Spotify
+    /// * `context` - What was requested, used in the error when the shape does not match
+    pub fn from_json(response: Value, context: &str) -> Result<Self, SpotifyError> {
+        let Value::Object(mut paging_object) = response else {
+            return Err(SpotifyError::InvalidResponse(format!("{} response is not a paging object", context)));
+        };
+
+        let total: usize = paging_object.get("total").and_then(Value::as_u64)
+            .ok_or(SpotifyError::InvalidResponse(format!("{} response has no total", context)))?
+            .try_into().unwrap();
+        let after: Option<String> = paging_object.get("cursors").and_then(|cursors| cursors["after"].as_str()).map(str::to_owned);
+        let Some(Value::Array(items)) = paging_object.remove("items") else {
+            return Err(SpotifyError::InvalidResponse(format!("{} response has no items", context)));
+        };
+
+        Ok(Self {
+            items,
+            total,
+            after
+        })
+    }
+}
@@ -1,135 +1,185 @@
 use rand::{distributions::Alphanumeric, Rng};
-use reqwest::{Client, Response, Error};
-use serde_json::Value;
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode, Url};
+use serde_json::{json, Value};
 use base64::{prelude::*};
 use sha2::{Sha256, Digest};
 
-use std::{collections::HashMap, fs::{self, File}, io::{Read, Write}, net::{TcpListener, TcpStream}, time::{SystemTime, UNIX_EPOCH}};
+use tokio::time;
+
+use std::{collections::HashMap, fs, io::Write, path::Path, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use super::{callback_server::{CallbackServer, DEFAULT_RESULT_PAGE}, error::SpotifyError, paging::Page, token_store::{StoredToken, TokenStore}};
+
+/// Scopes needed to export, always requested
+const READ_SCOPE: &str = "user-library-read user-read-playback-position playlist-read-private user-follow-read user-read-private user-read-recently-played user-top-read";
+/// Scopes requested by older versions, which did not record the scopes of their tokens
+const LEGACY_SCOPE: &str = "user-library-read user-read-playback-position playlist-read-private user-follow-read";
+/// Scopes needed to restore saved items and followed artists
+pub const LIBRARY_WRITE_SCOPE: &str = "user-library-modify user-follow-modify";
+/// Scopes needed to recreate and follow playlists
+pub const PLAYLIST_WRITE_SCOPE: &str = "playlist-modify-public playlist-modify-private";
+/// How often a request is retried when Spotify rate limits us
+const MAX_RATE_LIMIT_RETRIES: u32 = 5;
 
 pub struct SpotifyClient {
-    flow_type: String,
     spotify_client_id: String,
-    spotify_client_secret: String,
+    spotify_client_secret: Option<String>,
+    redirect_uri: String,
+    callback_timeout: Duration,
+    callback_page_path: Option<String>,
     code_verifier: String,
     code_challenge: String,
-    token_refreshed: u64,
+    token_store: Box<dyn TokenStore>,
     access_token: String,
     refresh_token: String,
     token_type: String,
-    expires_in: u64,
+    expires_at: u64,
+    /// Scopes to request when authorizing
+    scope: String,
+    /// Scopes granted to the current token
+    granted_scope: String,
+    /// Authorize in the browser even if the stored token is still accepted
+    reauthorize: bool,
     client: Client
 }
 
 impl SpotifyClient {
 
-    pub fn new(flow_type: String, spotify_client_id: String, spotify_client_secret: String) -> Self {
+    pub fn new(spotify_client_id: String, spotify_client_secret: Option<String>, redirect_uri: String, callback_timeout: Duration, callback_page_path: Option<String>, token_store: Box<dyn TokenStore>) -> Self {
         let code_verifier: String = "".to_string();
         let code_challenge: String = "".to_string();
-        let token_refreshed: u64 = 0;
         let access_token: String = "".to_string();
         let refresh_token: String = "".to_string();
         let token_type: String = "".to_string();
-        let expires_in: u64 = 0;
+        let expires_at: u64 = 0;
+        let scope: String = READ_SCOPE.to_string();
+        let granted_scope: String = "".to_string();
+        let reauthorize: bool = false;
         let client = Client::new();
 
         Self {
-            flow_type,
             spotify_client_id,
             spotify_client_secret,
+            redirect_uri,
+            callback_timeout,
+            callback_page_path,
             code_verifier,
             code_challenge,
-            token_refreshed,
+            token_store,
             access_token,
             refresh_token,
             token_type,
-            expires_in,
+            expires_at,
+            scope,
+            granted_scope,
+            reauthorize,
             client
         }
     }
 
-    /// Get access token for Spotify API
+    /// Adds scopes to request when authorizing, a stored token without them is replaced by authorizing again
+    ///
+    /// # Arguments
+    ///
+    /// * `scope` - Space separated scopes to add
+    pub fn request_scope(&mut self, scope: &str) {
+        self.scope = format!("{} {}", self.scope, scope);
+    }
+
+    /// Ignores the stored token so the next call to get_access_token authorizes in the browser again
+    pub fn reauthorize(&mut self) {
+        self.reauthorize = true;
+    }
+
+    /// Get access token for Spotify API using the Authorization Code flow with PKCE
     /// 
     /// This will open a browser window from Spotify asking the user to grant the privelages required to this script.
-    /// Once granted, Spotify will do a callback request which the script will catch and serve a callback html for.
-    /// This html file will, using javascript, extract the query parameters and do a request back to this script so that we can extract the access token here in the backend.
-    pub async fn get_access_token(&mut self) -> Result<bool, Error> {
+    /// Once granted, Spotify will do a callback request which the script will catch and read the authorization code from.
+    /// The user is then shown whether authorization succeeded, if no callback arrives within the callback timeout authorization fails.
+    pub async fn get_access_token(&mut self) -> Result<bool, SpotifyError> {
         let mut has_token: bool = false;
-        if self.flow_type.eq("code") {
-            if fs::exists("token.txt").unwrap() {
-                self.refresh_token = fs::read_to_string("token.txt").unwrap();
-
-                has_token = self.refresh_access_token_validity().await?;
-            }
+        if self.reauthorize {
+            println!("Ignoring the stored token, authorizing again");
+        } else if let Some(stored_token) = self.token_store.load()? {
+            self.refresh_token = stored_token.refresh_token;
+            self.access_token = stored_token.access_token;
+            self.token_type = stored_token.token_type;
+            self.expires_at = stored_token.expires_at;
+            self.granted_scope = stored_token.scope;
+
+            // tokens of older versions do not record their scopes, they were granted what older versions requested
+            let granted_scope: &str = if self.granted_scope.is_empty() { LEGACY_SCOPE } else { &self.granted_scope };
+            let missing_scope: Vec<&str> = self.scope.split_whitespace().filter(|scope| !granted_scope.split_whitespace().any(|granted| granted == *scope)).collect();
+
+            // a token missing scopes we need is replaced by authorizing again, a still valid access token is reused as is, otherwise it is refreshed here
+            // if Spotify no longer accepts the refresh token we fall back to authorizing in the browser
+            has_token = if !missing_scope.is_empty() {
+                println!("Stored token does not grant {}, authorizing again", missing_scope.join(" "));
+                false
+            } else {
+                match self.refresh_access_token_validity().await {
+                    Ok(valid) => valid,
+                    Err(SpotifyError::TokenRequest(error)) => {
+                        println!("Stored token is no longer accepted by Spotify({}), authorizing again", error);
+                        false
+                    }
+                    Err(e) => return Err(e)
+                }
+            };
         }
 
         if !has_token {
-            // start TCP Listener that will be used to receive callback requests as part of OAuth flow
-            let listener: TcpListener = TcpListener::bind("127.0.0.1:8000").expect("Failed to bind to address");
-
-            // generate random 16 length string to validate in implicit grant
+            // start the server that will be used to receive callback requests as part of OAuth flow
+            let result_page: String = match &self.callback_page_path {
+                Some(path) => fs::read_to_string(path).map_err(|e| SpotifyError::CallbackPage(path.to_owned(), e))?,
+                None => DEFAULT_RESULT_PAGE.to_owned()
+            };
+            let mut callback_server: CallbackServer = CallbackServer::bind(&self.redirect_uri, result_page).await?;
+
+            // generate random 16 length string to validate in the callback
             let state: String = rand::thread_rng().sample_iter(&Alphanumeric).take(16).map(char::from).collect();
-            let scope: &str = "user-library-read user-read-playback-position playlist-read-private user-follow-read";
 
-            let mut authorization_url: String = format!("https://accounts.spotify.com/authorize?response_type={}&client_id={}&scope={}&redirect_uri=http://localhost:8000/callback&state={}", self.flow_type, self.spotify_client_id, scope, state);
-            if self.flow_type.eq("code") {
-                self.generate_code_challenge();
-                authorization_url.push_str(&format!("&code_challenge_method=S256&code_challenge={}", self.code_challenge));
-            }
-            open::that(authorization_url).unwrap();
-
-            let mut running: bool = true;
-            while running {
-                match listener.accept() {
-                    Ok((mut stream, _)) => {
-                        let mut buffer = [0; 1024];
-                        stream.read(&mut buffer).expect("Failed to read request");
-
-                        let request: &str = std::str::from_utf8(&buffer).unwrap();
-
-                        let first_line: &str = request.lines().next().unwrap();
-                        let url: &str = first_line.split_whitespace().nth(1).unwrap();
-
-                        // we only expect 2 calls here, either the callback from spotify, or a finalize call from our own html
-                        if url.contains("finalizeAuthentication") {
-                            // if it is the finalize call we extract the relevant details from the URL and finalize the oauth flow
-                            if self.flow_type.eq("token") {
-                                self.finalize_implicit_grant(url, &state);
-                            } else {
-                                self.finalize_authorization_code(url, &state).await?; // TODO authorization code with PKCE, maybe?
-                            }
-
-                            running = false;
-                        } else {
-                            // if its not the finalize call we assume its the callback from spotify and serve our callback html
-                            self.serve_callback(&mut stream);
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Error: {}", e);
-                    }
-                }
+            self.generate_code_challenge();
+            let authorization_params: [(&str, &str); 7] = [
+                ("response_type", "code"),
+                ("client_id", &self.spotify_client_id),
+                ("scope", &self.scope),
+                ("redirect_uri", &self.redirect_uri),
+                ("state", &state),
+                ("code_challenge_method", "S256"),
+                ("code_challenge", &self.code_challenge)
+            ];
+            let authorization_url: Url = Url::parse_with_params("https://accounts.spotify.com/authorize", &authorization_params).unwrap();
+            if open::that(authorization_url.as_str()).is_err() {
+                println!("Could not open a browser, please open the following URL to authorize : {}", authorization_url);
             }
+
+            time::timeout(self.callback_timeout, self.handle_callback_requests(&mut callback_server, &state)).await
+                .map_err(|_| SpotifyError::CallbackTimeout(self.callback_timeout.as_secs()))??;
         }
 
         Ok(true)
     }
 
+    /// Retrieve the profile of the user the token belongs to
+    pub async fn get_current_user(&mut self) -> Result<Value, SpotifyError> {
+        let current_user: Value = self.get_json("https://api.spotify.com/v1/me").await?;
+        if !current_user["id"].is_string() {
+            return Err(SpotifyError::InvalidResponse("current user response has no id".to_string()));
+        }
+
+        Ok(current_user)
+    }
+
     /// Retrieve the saved tracks for the user
     ///
     /// # Arguments
     ///
     /// * `offset` - An int that specifies the offset in the list of saved tracks
     /// * `limit` - An int specifying total number of tracks to return, 50 is max
-    pub async fn get_saved_tracks(&mut self, offset: i32, limit: i32) -> Result<Value, Error> {
-        if !self.refresh_access_token_validity().await.unwrap() {
-            panic!("No valid token")
-        }
-
+    pub async fn get_saved_tracks(&mut self, offset: i32, limit: i32) -> Result<Page, SpotifyError> {
         let url: String = format!("https://api.spotify.com/v1/me/tracks?offset={}&limit={}", offset, limit);
-        let get_response: Response = self.client.get(url).header("Authorization", format!("{} {}", self.token_type, self.access_token)).send().await?;
-        let get_response_json: Value = serde_json::from_str(&get_response.text().await?).expect("JSON was not well-formatted");
-
-        Ok(get_response_json)
+        Page::from_json(self.get_json(&url).await?, "saved tracks")
     }
 
     /// Retrieve the saved albums for the user
@@ -138,16 +188,21 @@ impl SpotifyClient {
     ///
     /// * `offset` - An int that specifies the offset in the list of saved albums
     /// * `limit` - An int specifying total number of albums to return, 50 is max
-    pub async fn get_saved_albums(&mut self, offset: i32, limit: i32) -> Result<Value, Error> {
-        if !self.refresh_access_token_validity().await.unwrap() {
-            panic!("No valid token")
-        }
-
+    pub async fn get_saved_albums(&mut self, offset: i32, limit: i32) -> Result<Page, SpotifyError> {
         let url: String = format!("https://api.spotify.com/v1/me/albums?offset={}&limit={}", offset, limit);
-        let get_response: Response = self.client.get(url).header("Authorization", format!("{} {}", self.token_type, self.access_token)).send().await?;
-        let get_response_json: Value = serde_json::from_str(&get_response.text().await?).expect("JSON was not well-formatted");
+        Page::from_json(self.get_json(&url).await?, "saved albums")
+    }
 
-        Ok(get_response_json)
+    /// Retrieve the tracks of the album for the given album id
+    ///
+    /// # Arguments
+    ///
+    /// * `album_id` - The id of the album to retrieve tracks for
+    /// * `offset` - An int that specifies the offset in the list of tracks
+    /// * `limit` - An int specifying total number of tracks to return, 50 is max
+    pub async fn get_album_tracks(&mut self, album_id: &str, offset: i32, limit: i32) -> Result<Page, SpotifyError> {
+        let url: String = format!("https://api.spotify.com/v1/albums/{}/tracks?offset={}&limit={}", album_id, offset, limit);
+        Page::from_json(self.get_json(&url).await?, "album tracks")
     }
 
     /// Retrieve the saved audiobooks for the user
@@ -156,16 +211,21 @@ impl SpotifyClient {
     ///
     /// * `offset` - An int that specifies the offset in the list of saved audiobooks
     /// * `limit` - An int specifying total number of audiobooks to return, 50 is max
-    pub async fn get_saved_audiobooks(&mut self, offset: i32, limit: i32) -> Result<Value, Error> {
-        if !self.refresh_access_token_validity().await.unwrap() {
-            panic!("No valid token")
-        }
-
+    pub async fn get_saved_audiobooks(&mut self, offset: i32, limit: i32) -> Result<Page, SpotifyError> {
         let url: String = format!("https://api.spotify.com/v1/me/audiobooks?offset={}&limit={}", offset, limit);
-        let get_response: Response = self.client.get(url).header("Authorization", format!("{} {}", self.token_type, self.access_token)).send().await?;
-        let get_response_json: Value = serde_json::from_str(&get_response.text().await?).expect("JSON was not well-formatted");
+        Page::from_json(self.get_json(&url).await?, "saved audiobooks")
+    }
 
-        Ok(get_response_json)
+    /// Retrieve the chapters of the audiobook for the given audiobook id, with the resume point of the user
+    ///
+    /// # Arguments
+    ///
+    /// * `audiobook_id` - The id of the audiobook to retrieve chapters for
+    /// * `offset` - An int that specifies the offset in the list of chapters
+    /// * `limit` - An int specifying total number of chapters to return, 50 is max
+    pub async fn get_audiobook_chapters(&mut self, audiobook_id: &str, offset: i32, limit: i32) -> Result<Page, SpotifyError> {
+        let url: String = format!("https://api.spotify.com/v1/audiobooks/{}/chapters?offset={}&limit={}", audiobook_id, offset, limit);
+        Page::from_json(self.get_json(&url).await?, "audiobook chapters")
     }
 
     /// Retrieve the saved episodes for the user
@@ -174,16 +234,9 @@ impl SpotifyClient {
     ///
     /// * `offset` - An int that specifies the offset in the list of saved episodes
     /// * `limit` - An int specifying total number of episodes to return, 50 is max
-    pub async fn get_saved_episodes(&mut self, offset: i32, limit: i32) -> Result<Value, Error> {
-        if !self.refresh_access_token_validity().await.unwrap() {
-            panic!("No valid token")
-        }
-
+    pub async fn get_saved_episodes(&mut self, offset: i32, limit: i32) -> Result<Page, SpotifyError> {
         let url: String = format!("https://api.spotify.com/v1/me/episodes?offset={}&limit={}", offset, limit);
-        let get_response: Response = self.client.get(url).header("Authorization", format!("{} {}", self.token_type, self.access_token)).send().await?;
-        let get_response_json: Value = serde_json::from_str(&get_response.text().await?).expect("JSON was not well-formatted");
-
-        Ok(get_response_json)
+        Page::from_json(self.get_json(&url).await?, "saved episodes")
     }
 
     /// Retrieve the owned or followed playlists for the user
@@ -192,16 +245,9 @@ impl SpotifyClient {
     ///
     /// * `offset` - An int that specifies the offset in the list of playlists
     /// * `limit` - An int specifying total number of playlists to return, 50 is max
-    pub async fn get_owned_followed_playlists(&mut self, offset: i32, limit: i32) -> Result<Value, Error> {
-        if !self.refresh_access_token_validity().await.unwrap() {
-            panic!("No valid token")
-        }
-
+    pub async fn get_owned_followed_playlists(&mut self, offset: i32, limit: i32) -> Result<Page, SpotifyError> {
         let url: String = format!("https://api.spotify.com/v1/me/playlists?offset={}&limit={}", offset, limit);
-        let get_response: Response = self.client.get(url).header("Authorization", format!("{} {}", self.token_type, self.access_token)).send().await?;
-        let get_response_json: Value = serde_json::from_str(&get_response.text().await?).expect("JSON was not well-formatted");
-
-        Ok(get_response_json)
+        Page::from_json(self.get_json(&url).await?, "playlists")
     }
 
     /// Retrieve the tracks of the playlist for the given playlist id
@@ -211,17 +257,10 @@ impl SpotifyClient {
     /// * `playlist_id` - The id of the playlist to retrieve tracks for
     /// * `offset` - An int that specifies the offset in the list of tracks
     /// * `limit` - An int specifying total number of tracks to return, 50 is max
-    pub async fn get_playlist_tracks(&mut self, playlist_id: &str, offset: i32, limit: i32) -> Result<Value, Error> {
-        if !self.refresh_access_token_validity().await.unwrap() {
-            panic!("No valid token")
-        }
-
-        let fields: &str = "items(added_by.id,added_at,track(id,name,album(album_type,name,release_date,artists(id,name)),artists(id,name)))"; // the fields specifier for track.album.artists has no affect
+    pub async fn get_playlist_tracks(&mut self, playlist_id: &str, offset: i32, limit: i32) -> Result<Page, SpotifyError> {
+        let fields: &str = "total,items(added_by.id,added_at,is_local,track(id,uri,name,album(album_type,name,release_date,artists(id,name)),artists(id,name)))"; // the fields specifier for track.album.artists has no affect
         let url: String = format!("https://api.spotify.com/v1/playlists/{}/tracks?fields={}&offset={}&limit={}", playlist_id, fields, offset, limit);
-        let get_response: Response = self.client.get(url).header("Authorization", format!("{} {}", self.token_type, self.access_token)).send().await?;
-        let get_response_json: Value = serde_json::from_str(&get_response.text().await?).expect("JSON was not well-formatted");
-
-        Ok(get_response_json)
+        Page::from_json(self.get_json(&url).await?, "playlist tracks")
     }
 
     /// Retrieve the saved shows for the user
@@ -230,151 +269,336 @@ impl SpotifyClient {
     ///
     /// * `offset` - An int that specifies the offset in the list of saved shows
     /// * `limit` - An int specifying total number of shows to return, 50 is max
-    pub async fn get_saved_shows(&mut self, offset: i32, limit: i32) -> Result<Value, Error> {
-        if !self.refresh_access_token_validity().await.unwrap() {
-            panic!("No valid token")
-        }
-
+    pub async fn get_saved_shows(&mut self, offset: i32, limit: i32) -> Result<Page, SpotifyError> {
         let url: String = format!("https://api.spotify.com/v1/me/shows?offset={}&limit={}", offset, limit);
-        let get_response: Response = self.client.get(url).header("Authorization", format!("{} {}", self.token_type, self.access_token)).send().await?;
-        let get_response_json: Value = serde_json::from_str(&get_response.text().await?).expect("JSON was not well-formatted");
+        Page::from_json(self.get_json(&url).await?, "saved shows")
+    }
 
-        Ok(get_response_json)
+    /// Retrieve the episodes of the show for the given show id, with the resume point of the user
+    ///
+    /// # Arguments
+    ///
+    /// * `show_id` - The id of the show to retrieve episodes for
+    /// * `offset` - An int that specifies the offset in the list of episodes
+    /// * `limit` - An int specifying total number of episodes to return, 50 is max
+    pub async fn get_show_episodes(&mut self, show_id: &str, offset: i32, limit: i32) -> Result<Page, SpotifyError> {
+        let url: String = format!("https://api.spotify.com/v1/shows/{}/episodes?offset={}&limit={}", show_id, offset, limit);
+        Page::from_json(self.get_json(&url).await?, "show episodes")
     }
 
     /// Retrieve the followed artists for the user
     ///
     /// # Arguments
     ///
-    /// * `offset` - An int that specifies the offset in the list of followed artists
+    /// * `after` - The cursor of the last page retrieved, empty for the first page
     /// * `limit` - An int specifying total number of artists to return, 50 is max
-    pub async fn get_followed_artists(&mut self, after: &str, limit: i32) -> Result<Value, Error> {
-        if !self.refresh_access_token_validity().await.unwrap() {
-            panic!("No valid token")
-        }
-
-        let url: String;
-        if after == "" {
-            url = format!("https://api.spotify.com/v1/me/following?type=artist&limit={}", limit);
+    pub async fn get_followed_artists(&mut self, after: &str, limit: i32) -> Result<Page, SpotifyError> {
+        let url: String = if after.is_empty() {
+            format!("https://api.spotify.com/v1/me/following?type=artist&limit={}", limit)
         } else {
-            url = format!("https://api.spotify.com/v1/me/following?type=artist&after={}&limit={}", after, limit);
-        }
+            format!("https://api.spotify.com/v1/me/following?type=artist&after={}&limit={}", after, limit)
+        };
+
+        // followed artists are wrapped in an object keyed by type
+        Page::from_json(self.get_json(&url).await?["artists"].take(), "followed artists")
+    }
+
+    /// Retrieve the tracks the user played most recently, Spotify only keeps the last 50
+    ///
+    /// # Arguments
+    ///
+    /// * `after` - Unix timestamp in milliseconds, only plays after it are returned
+    /// * `limit` - An int specifying total number of plays to return, 50 is max
+    ///
+    /// # Returns
+    /// The plays, most recent first
+    pub async fn get_recently_played(&mut self, after: i64, limit: i32) -> Result<Vec<Value>, SpotifyError> {
+        let url: String = format!("https://api.spotify.com/v1/me/player/recently-played?after={}&limit={}", after, limit);
+        let Value::Array(items) = self.get_json(&url).await?["items"].take() else {
+            return Err(SpotifyError::InvalidResponse("recently played response has no items".to_string()));
+        };
+
+        Ok(items)
+    }
+
+    /// Retrieve the artists or tracks the user listens to most
+    ///
+    /// # Arguments
+    ///
+    /// * `item_type` - artists or tracks
+    /// * `time_range` - short_term(about 4 weeks), medium_term(about 6 months) or long_term(about a year)
+    /// * `offset` - An int that specifies the offset in the list of top items
+    /// * `limit` - An int specifying total number of items to return, 50 is max
+    pub async fn get_top_items(&mut self, item_type: &str, time_range: &str, offset: i32, limit: i32) -> Result<Page, SpotifyError> {
+        let url: String = format!("https://api.spotify.com/v1/me/top/{}?time_range={}&offset={}&limit={}", item_type, time_range, offset, limit);
+        Page::from_json(self.get_json(&url).await?, "top items")
+    }
+
+    /// Retrieve the full artist objects of the given artists
+    ///
+    /// # Arguments
+    ///
+    /// * `ids` - The ids of the artists, at most 50
+    ///
+    /// # Returns
+    /// The artists in the order of the ids, null for ids Spotify does not know
+    pub async fn get_artists(&mut self, ids: &[&str]) -> Result<Vec<Value>, SpotifyError> {
+        let url: String = format!("https://api.spotify.com/v1/artists?ids={}", ids.join(","));
+        let Value::Array(artists) = self.get_json(&url).await?["artists"].take() else {
+            return Err(SpotifyError::InvalidResponse("artists response has no artists".to_string()));
+        };
+
+        Ok(artists)
+    }
 
-        let get_response: Response = self.client.get(url).header("Authorization", format!("{} {}", self.token_type, self.access_token)).send().await?;
-        let get_response_json: Value = serde_json::from_str(&get_response.text().await?).expect("JSON was not well-formatted");
+    /// Check which items are saved in the library of the user
+    ///
+    /// # Arguments
+    ///
+    /// * `library` - The kind of saved items, eg tracks or albums
+    /// * `ids` - The ids of the items, at most 50(20 for albums)
+    pub async fn check_saved(&mut self, library: &str, ids: &[&str]) -> Result<Vec<bool>, SpotifyError> {
+        let url: String = format!("https://api.spotify.com/v1/me/{}/contains?ids={}", library, ids.join(","));
+        bool_list(self.get_json(&url).await?, ids.len(), library)
+    }
 
-        Ok(get_response_json)
+    /// Save items to the library of the user
+    ///
+    /// # Arguments
+    ///
+    /// * `library` - The kind of saved items, eg tracks or albums
+    /// * `ids` - The ids of the items, at most 50(20 for albums)
+    pub async fn save_to_library(&mut self, library: &str, ids: &[&str]) -> Result<(), SpotifyError> {
+        // shows and audiobooks only take the ids in the query, which the other libraries accept as well
+        let url: String = format!("https://api.spotify.com/v1/me/{}?ids={}", library, ids.join(","));
+        self.put_json(&url, &json!({})).await?;
+
+        Ok(())
     }
 
-    /// Serves the html file in src/html/callback.html as response on the TcpStream
+    /// Check which artists the user follows
     ///
     /// # Arguments
     ///
-    /// * `stream` - The TCP stream to serve the response on
-    fn serve_callback(&mut self, stream: &mut TcpStream) {
-        let content: String = std::fs::read_to_string("src/html/callback.html").unwrap_or_else(|_| {
-            "Failed to read the HTML file".to_string()
-        });
+    /// * `ids` - The ids of the artists, at most 50
+    pub async fn check_followed_artists(&mut self, ids: &[&str]) -> Result<Vec<bool>, SpotifyError> {
+        let url: String = format!("https://api.spotify.com/v1/me/following/contains?type=artist&ids={}", ids.join(","));
+        bool_list(self.get_json(&url).await?, ids.len(), "followed artists")
+    }
 
-        let response: String = format!(
-            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n{}",
-            content
-        );
+    /// Follow artists as the user
+    ///
+    /// # Arguments
+    ///
+    /// * `ids` - The ids of the artists, at most 50
+    pub async fn follow_artists(&mut self, ids: &[&str]) -> Result<(), SpotifyError> {
+        self.put_json("https://api.spotify.com/v1/me/following?type=artist", &json!({ "ids": ids })).await?;
 
-        stream.write(response.as_bytes()).expect("Failed to write response");
-        stream.flush().expect("Failed to flush stream");
+        Ok(())
     }
 
-    /// Extracts the access_token and other properties for the Spotify API from the url
+    /// Create a playlist owned by the user
     ///
     /// # Arguments
     ///
-    /// * `url` - The URL to extract the query paramters from
-    /// * `state` - State string provided to Spotify in initial request that must match
-    fn finalize_implicit_grant(&mut self, url: &str, state: &str) {
-        let query_params = url.split("?").nth(1).unwrap().split("&");
-
-        for param in query_params {
-            let mut param_arr = param.split("=");
-            match param_arr.next().unwrap() {
-                "access_token" => {
-                    self.access_token = param_arr.next().unwrap().to_owned();
-                }, "token_type" => {
-                    self.token_type = param_arr.next().unwrap().to_owned();
-                }, "expires_in" => {
-                    self.expires_in = param_arr.next().unwrap().parse().unwrap();
-                }, "state" => {
-                    if state != param_arr.next().unwrap() {
-                        panic!("State does not match")
-                    }
-                }, _ => {
-                    // dont care
-                }
+    /// * `user_id` - The id of the user to create the playlist for
+    /// * `playlist` - The name, description, public and collaborative settings of the playlist
+    ///
+    /// # Returns
+    /// The id of the created playlist
+    pub async fn create_playlist(&mut self, user_id: &str, playlist: &Value) -> Result<String, SpotifyError> {
+        let url: String = format!("https://api.spotify.com/v1/users/{}/playlists", user_id);
+        let created_playlist: Value = self.post_json(&url, playlist).await?;
+
+        created_playlist["id"].as_str().map(str::to_owned).ok_or(SpotifyError::InvalidResponse("created playlist has no id".to_string()))
+    }
+
+    /// Add items to the end of a playlist
+    ///
+    /// # Arguments
+    ///
+    /// * `playlist_id` - The id of the playlist to add to
+    /// * `uris` - The Spotify URIs of the tracks and episodes to add, at most 100
+    pub async fn add_playlist_items(&mut self, playlist_id: &str, uris: &[&str]) -> Result<(), SpotifyError> {
+        let url: String = format!("https://api.spotify.com/v1/playlists/{}/tracks", playlist_id);
+        self.post_json(&url, &json!({ "uris": uris })).await?;
+
+        Ok(())
+    }
+
+    /// Follow a playlist as the user
+    ///
+    /// # Arguments
+    ///
+    /// * `playlist_id` - The id of the playlist to follow
+    pub async fn follow_playlist(&mut self, playlist_id: &str) -> Result<(), SpotifyError> {
+        let url: String = format!("https://api.spotify.com/v1/playlists/{}/followers", playlist_id);
+        self.put_json(&url, &json!({})).await?;
+
+        Ok(())
+    }
+
+    /// Does a GET request to the Spotify API and parses the JSON response
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The API URL to request
+    async fn get_json(&mut self, url: &str) -> Result<Value, SpotifyError> {
+        self.request_json(Method::GET, url, None).await
+    }
+
+    /// Does a PUT request to the Spotify API with a JSON body and parses the JSON response
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The API URL to request
+    /// * `body` - The JSON body to send
+    async fn put_json(&mut self, url: &str, body: &Value) -> Result<Value, SpotifyError> {
+        self.request_json(Method::PUT, url, Some(body)).await
+    }
+
+    /// Does a POST request to the Spotify API with a JSON body and parses the JSON response
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The API URL to request
+    /// * `body` - The JSON body to send
+    async fn post_json(&mut self, url: &str, body: &Value) -> Result<Value, SpotifyError> {
+        self.request_json(Method::POST, url, Some(body)).await
+    }
+
+    /// Sends a request to the Spotify API, refreshing the token when it is rejected and waiting out rate limits
+    /// If Spotify rejects the access token it is refreshed and the request retried once, if that does not help the user has to authorize again
+    ///
+    /// # Arguments
+    ///
+    /// * `method` - The HTTP method of the request
+    /// * `url` - The API URL to request
+    /// * `body` - The JSON body to send, if any
+    ///
+    /// # Returns
+    /// The JSON response, null if Spotify responded without a body
+    async fn request_json(&mut self, method: Method, url: &str, body: Option<&Value>) -> Result<Value, SpotifyError> {
+        self.refresh_access_token_validity().await.map_err(reauthorization_required)?;
+
+        let mut response: Response = self.send_request(method.clone(), url, body).await?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            // the token was revoked or expired before we expected it to, force a refresh and try again
+            self.access_token.clear();
+            self.refresh_access_token_validity().await.map_err(reauthorization_required)?;
+
+            response = self.send_request(method.clone(), url, body).await?;
+            if response.status() == StatusCode::UNAUTHORIZED {
+                return Err(SpotifyError::ReauthorizationRequired("the refreshed access token was rejected".to_string()));
             }
         }
+
+        let mut retries: u32 = 0;
+        while response.status() == StatusCode::TOO_MANY_REQUESTS && retries < MAX_RATE_LIMIT_RETRIES {
+            let retry_after: u64 = response.headers().get("Retry-After").and_then(|value| value.to_str().ok()).and_then(|value| value.parse().ok()).unwrap_or(1);
+            time::sleep(Duration::from_secs(retry_after)).await;
+
+            retries += 1;
+            response = self.send_request(method.clone(), url, body).await?;
+        }
+
+        let status: StatusCode = response.status();
+        let response_text: String = response.text().await?;
+        if response_text.trim().is_empty() && status.is_success() {
+            return Ok(Value::Null);
+        }
+        let response_json: Value = serde_json::from_str(&response_text)
+            .map_err(|_| SpotifyError::InvalidResponse(format!("{} returned {} without a JSON body", url, status)))?;
+
+        // errors come back as {"error": {"status": <status>, "message": <message>}}
+        if let Some(error) = response_json["error"].as_object() {
+            return Err(SpotifyError::Api {
+                status: error.get("status").and_then(Value::as_u64).and_then(|error_status| u16::try_from(error_status).ok()).unwrap_or(status.as_u16()),
+                message: error.get("message").and_then(Value::as_str).unwrap_or_default().to_owned()
+            });
+        }
+        if !status.is_success() {
+            return Err(SpotifyError::Api { status: status.as_u16(), message: status.canonical_reason().unwrap_or_default().to_owned() });
+        }
+
+        Ok(response_json)
+    }
+
+    async fn send_request(&self, method: Method, url: &str, body: Option<&Value>) -> Result<Response, SpotifyError> {
+        let mut request: RequestBuilder = self.client.request(method, url).header("Authorization", format!("{} {}", self.token_type, self.access_token));
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+
+        Ok(request.send().await?)
     }
 
-    /// Extracts the code for the Spotify API from the url
-    /// Then continues the OAuth2.0 Authorization Code flow by using the code to request an access token
+    /// Handles requests on the callback server until the OAuth flow has been finalized
+    /// Requests without the matching state are answered with an error but do not end the flow, only the callback with the state or an error from Spotify does
     ///
     /// # Arguments
     ///
-    /// * `url` - The URL to extract the query paramters from
+    /// * `callback_server` - The server receiving the callback requests
     /// * `state` - State string provided to Spotify in initial request that must match
-    async fn finalize_authorization_code(&mut self, url: &str, state: &str) -> Result<bool, Error> {
-        let query_params = url.split("?").nth(1).unwrap().split("&");
-
-        for param in query_params {
-            let mut param_arr = param.split("=");
-            match param_arr.next().unwrap() {
-                "code" => {
-                    let authorization_code: String = param_arr.next().unwrap().to_owned();
-                    let mut form_params = HashMap::new();
-                    form_params.insert("grant_type", "authorization_code");
-                    form_params.insert("code", &authorization_code);
-                    form_params.insert("redirect_uri", "http://localhost:8000/callback");
-                    form_params.insert("client_id", &self.spotify_client_id);
-                    form_params.insert("code_verifier", &self.code_verifier);
-
-                    let access_token_url: &str = "https://accounts.spotify.com/api/token";
-                    let access_token_response: Response = self.client.post(access_token_url)
-                                                                .header("Content-Type", "application/x-www-form-urlencoded")
-                                                                .form(&form_params)
-                                                                .send().await?;
-                    let access_token_response_json: Value = serde_json::from_str(&access_token_response.text().await?).expect("JSON was not well-formatted");
-                    self.access_token = access_token_response_json["access_token"].as_str().unwrap().to_owned();
-                    self.refresh_token = access_token_response_json["refresh_token"].as_str().unwrap().to_owned();
-                    self.token_type = access_token_response_json["token_type"].as_str().unwrap().to_owned();
-                    self.expires_in = access_token_response_json["expires_in"].as_u64().unwrap().to_owned();
-                    self.token_refreshed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-
-                    let mut token_file: File = File::create("token.txt").unwrap();
-                    token_file.write_all(self.refresh_token.as_bytes()).unwrap();
-                }, "state" => {
-                    if state != param_arr.next().unwrap() {
-                        panic!("State does not match")
-                    }
-                }, _ => {
-                    // dont care
-                }
+    async fn handle_callback_requests(&mut self, callback_server: &mut CallbackServer, state: &str) -> Result<(), SpotifyError> {
+        while let Some(request) = callback_server.next_request().await {
+            // reloads of stale tabs, prefetches and other stray hits on the callback path keep us waiting for the real callback,
+            // spotify includes the state in its error redirects as well so errors without it are ignored too
+            if request.query.get("state").map(String::as_str) != Some(state) {
+                request.respond_result(Some(&SpotifyError::StateMismatch)).await;
+                continue;
             }
+
+            // the callback from spotify carries the code in the query, so the oauth flow can be finalized straight away
+            let result: Result<(), SpotifyError> = self.finalize_authorization_code(&request.query, state).await;
+
+            request.respond_result(result.as_ref().err()).await;
+            return result;
         }
 
-        Ok(true)
+        Ok(())
+    }
+
+    /// Extracts the code for the Spotify API from the query parameters
+    /// Then continues the OAuth2.0 Authorization Code flow by using the code to request an access token
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query paramters of the callback
+    /// * `state` - State string provided to Spotify in initial request that must match
+    async fn finalize_authorization_code(&mut self, query: &HashMap<String, String>, state: &str) -> Result<(), SpotifyError> {
+        check_callback_query(query, state)?;
+
+        let authorization_code: &String = query.get("code").ok_or(SpotifyError::MissingCallbackParameter("code"))?;
+        let mut form_params = HashMap::new();
+        form_params.insert("grant_type", "authorization_code");
+        form_params.insert("code", authorization_code);
+        form_params.insert("redirect_uri", &self.redirect_uri);
+        form_params.insert("client_id", &self.spotify_client_id);
+        form_params.insert("code_verifier", &self.code_verifier);
+
+        let access_token_url: &str = "https://accounts.spotify.com/api/token";
+        let access_token_response: Response = self.token_request(access_token_url)
+                                                    .form(&form_params)
+                                                    .send().await?;
+        let access_token_response_json: Value = token_response_json(access_token_response).await?;
+
+        self.access_token = token_field(&access_token_response_json, "access_token")?;
+        self.refresh_token = token_field(&access_token_response_json, "refresh_token")?;
+        self.token_type = token_field(&access_token_response_json, "token_type")?;
+        self.expires_at = now_secs() + token_expires_in(&access_token_response_json)?;
+        self.granted_scope = access_token_response_json["scope"].as_str().unwrap_or(&self.scope).to_owned();
+
+        self.save_token()
     }
 
     /// Uses the stored refresh token to refresh the access token if it has expired or has not been retrieved yet.
     /// 
     /// # Returns
     /// True if a valid token has been retrieved
-    async fn refresh_access_token_validity(&mut self) -> Result<bool, Error> {
-        // only refresh token for authorization code flow
-        if !self.flow_type.eq("code") {
-            return Ok(true)
-        }
-
-        let now_secs: u64 = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-        if self.token_refreshed == 0 || (self.token_refreshed + self.expires_in) < (now_secs - 300) {
+    async fn refresh_access_token_validity(&mut self) -> Result<bool, SpotifyError> {
+        // refresh a bit before expiry so the token does not expire in the middle of a request
+        if self.access_token.is_empty() || self.expires_at < now_secs() + 300 {
             std::io::stdout().flush().unwrap();
             let mut form_params = HashMap::new();
             form_params.insert("grant_type", "refresh_token");
@@ -382,32 +606,143 @@ impl SpotifyClient {
             form_params.insert("client_id", &self.spotify_client_id);
 
             let refresh_token_url: &str = "https://accounts.spotify.com/api/token";
-            let auth_header: String = format!("Basic {}", BASE64_STANDARD.encode(format!("{}:{}", self.spotify_client_id, self.spotify_client_secret)));
-            let access_token_response: Response = self.client.post(refresh_token_url)
-                                                        .header("Authorization", auth_header)
-                                                        .header("Content-Type", "application/x-www-form-urlencoded")
+            let access_token_response: Response = self.token_request(refresh_token_url)
                                                         .form(&form_params)
                                                         .send().await?;
-            let access_token_response_json: Value = serde_json::from_str(&access_token_response.text().await?).expect("JSON was not well-formatted");
-            self.access_token = access_token_response_json["access_token"].as_str().unwrap().to_owned();
-            self.token_type = access_token_response_json["token_type"].as_str().unwrap().to_owned();
-            self.expires_in = access_token_response_json["expires_in"].as_u64().unwrap().to_owned();
-            self.token_refreshed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            let access_token_response_json: Value = token_response_json(access_token_response).await?;
 
-            if !access_token_response_json["refresh_token"].is_null() {
-                self.refresh_token = access_token_response_json["refresh_token"].as_str().unwrap().to_owned();
+            self.access_token = token_field(&access_token_response_json, "access_token")?;
+            self.token_type = token_field(&access_token_response_json, "token_type")?;
+            self.expires_at = now_secs() + token_expires_in(&access_token_response_json)?;
 
-                let mut token_file: File = File::create("token.txt").unwrap();
-                token_file.write_all(self.refresh_token.as_bytes()).unwrap();
+            if !access_token_response_json["refresh_token"].is_null() {
+                self.refresh_token = token_field(&access_token_response_json, "refresh_token")?;
             }
+            if let Some(scope) = access_token_response_json["scope"].as_str() {
+                self.granted_scope = scope.to_owned();
+            }
+            self.save_token()?;
         }
 
         Ok(true)
     }
 
+    /// Where the tokens are stored between runs
+    pub fn token_location(&self) -> &Path {
+        self.token_store.location()
+    }
+
+    /// Saves the current tokens to the token store
+    fn save_token(&self) -> Result<(), SpotifyError> {
+        self.token_store.save(&StoredToken {
+            refresh_token: self.refresh_token.clone(),
+            access_token: self.access_token.clone(),
+            token_type: self.token_type.clone(),
+            expires_at: self.expires_at,
+            scope: self.granted_scope.clone()
+        })
+    }
+
+    /// Builds a request to the Spotify token endpoint
+    /// With PKCE the client id in the form identifies us, the client secret is only sent when it has been configured
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The token endpoint URL
+    fn token_request(&self, url: &str) -> RequestBuilder {
+        let request: RequestBuilder = self.client.post(url).header("Content-Type", "application/x-www-form-urlencoded");
+
+        match &self.spotify_client_secret {
+            Some(spotify_client_secret) => {
+                let auth_header: String = format!("Basic {}", BASE64_STANDARD.encode(format!("{}:{}", self.spotify_client_id, spotify_client_secret)));
+                request.header("Authorization", auth_header)
+            }
+            None => request
+        }
+    }
+
     fn generate_code_challenge(&mut self) {
         self.code_verifier = rand::thread_rng().sample_iter(&Alphanumeric).take(128).map(char::from).collect();
         let code_verifier_hashed = Sha256::digest(self.code_verifier.as_bytes());
         self.code_challenge = BASE64_STANDARD.encode(code_verifier_hashed).replace("/", "_").replace("+", "-").replace("=", "");
     }
+}
+
+/// Checks the state of the OAuth response parameters matches, then checks them for an error returned by Spotify
+///
+/// # Arguments
+///
+/// * `query` - The query paramters of the callback
+/// * `state` - State string provided to Spotify in initial request that must match
+fn check_callback_query(query: &HashMap<String, String>, state: &str) -> Result<(), SpotifyError> {
+    if query.get("state").map(String::as_str) != Some(state) {
+        return Err(SpotifyError::StateMismatch);
+    }
+    if let Some(error) = query.get("error") {
+        return Err(SpotifyError::AuthorizationDenied(error.to_owned()));
+    }
+
+    Ok(())
+}
+
+/// Reads the response of the token endpoint, which is not always JSON when accounts.spotify.com is down or rate limits us
+///
+/// # Arguments
+///
+/// * `response` - The response of the token endpoint
+///
+/// # Returns
+/// The JSON response, or the error Spotify gave for rejecting the request
+async fn token_response_json(response: Response) -> Result<Value, SpotifyError> {
+    let status: StatusCode = response.status();
+    let response_json: Option<Value> = serde_json::from_str(&response.text().await?).ok();
+
+    match response_json {
+        Some(response_json) if !response_json["error"].is_null() => {
+            let error: &str = response_json["error_description"].as_str().or(response_json["error"].as_str()).unwrap_or_default();
+            Err(SpotifyError::TokenRequest(error.to_owned()))
+        }
+        // only an error from spotify means the token is rejected, so an outage does not start authorizing in the browser again
+        _ if !status.is_success() => Err(SpotifyError::InvalidResponse(format!("the token endpoint responded with {}", status))),
+        Some(response_json) => Ok(response_json),
+        None => Err(SpotifyError::InvalidResponse("the token endpoint did not respond with JSON".to_string()))
+    }
+}
+
+/// Turns a failed refresh of the access token in the middle of an export into an error asking the user to authorize again
+fn reauthorization_required(e: SpotifyError) -> SpotifyError {
+    match e {
+        SpotifyError::TokenRequest(error) | SpotifyError::InvalidResponse(error) => SpotifyError::ReauthorizationRequired(error),
+        e => e
+    }
+}
+
+/// Reads a text field of the token endpoint response
+fn token_field(response_json: &Value, field: &str) -> Result<String, SpotifyError> {
+    response_json[field].as_str().map(str::to_owned).ok_or(SpotifyError::InvalidResponse(format!("token response without {}", field)))
+}
+
+/// Reads how many seconds the access token of the token endpoint response is valid for
+fn token_expires_in(response_json: &Value) -> Result<u64, SpotifyError> {
+    response_json["expires_in"].as_u64().ok_or(SpotifyError::InvalidResponse("token response without expires_in".to_string()))
+}
+
+/// Validates a response holding one boolean per requested id, as returned by the contains endpoints
+///
+/// # Arguments
+///
+/// * `response` - The response returned by Spotify
+/// * `expected` - The number of ids requested
+/// * `context` - What was requested, used in the error when the shape does not match
+fn bool_list(response: Value, expected: usize, context: &str) -> Result<Vec<bool>, SpotifyError> {
+    let bools: Option<Vec<bool>> = response.as_array().map(|values| values.iter().filter_map(Value::as_bool).collect());
+
+    match bools {
+        Some(bools) if bools.len() == expected => Ok(bools),
+        _ => Err(SpotifyError::InvalidResponse(format!("{} contains response does not hold {} booleans", context, expected)))
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
 }
\ No newline at end of file
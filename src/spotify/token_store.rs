@@ -0,0 +1,315 @@
+use argon2::Argon2;
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, Key, KeyInit, Nonce};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+
+use std::{fs::{self, DirBuilder, OpenOptions}, io::Write, path::{Path, PathBuf}};
+
+use super::error::SpotifyError;
+
+/// Marks the start of an encrypted token file, the trailing digit is the format version
+const ENCRYPTED_MAGIC: &[u8; 8] = b"SPXTOK01";
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+
+/// Tokens kept between runs so the user does not have to authorize every time
+#[derive(Serialize, Deserialize)]
+pub struct StoredToken {
+    pub refresh_token: String,
+    pub access_token: String,
+    pub token_type: String,
+    /// Unix timestamp in seconds after which the access token is no longer valid
+    pub expires_at: u64,
+    /// Scopes granted to the token, empty for tokens stored by older versions
+    #[serde(default)]
+    pub scope: String
+}
+
+/// Somewhere to persist tokens between runs
+pub trait TokenStore {
+    /// Loads the stored token, or none if nothing has been stored yet
+    fn load(&self) -> Result<Option<StoredToken>, SpotifyError>;
+
+    /// Stores the token, replacing any previously stored token
+    fn save(&self, token: &StoredToken) -> Result<(), SpotifyError>;
+
+    /// Where the token is stored, used to tell the user
+    fn location(&self) -> &Path;
+}
+
+/// Stores the token as plain JSON in a file only readable by the current user
+pub struct PlainFileTokenStore {
+    path: PathBuf
+}
+
+/// Where the key used to encrypt the token file comes from
+pub enum TokenKey {
+    Passphrase(String),
+    KeyFile(PathBuf)
+}
+
+/// Stores the token in a file encrypted with ChaCha20-Poly1305, keyed by a passphrase or key file through Argon2
+pub struct EncryptedFileTokenStore {
+    path: PathBuf,
+    key: TokenKey
+}
+
+impl PlainFileTokenStore {
+
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path
+        }
+    }
+}
+
+impl TokenStore for PlainFileTokenStore {
+
+    fn load(&self) -> Result<Option<StoredToken>, SpotifyError> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+
+        let contents: Vec<u8> = fs::read(&self.path).map_err(|e| SpotifyError::TokenStore(format!("Could not read {}: {}", self.path.display(), e)))?;
+        let token: StoredToken = serde_json::from_slice(&contents).map_err(|e| SpotifyError::TokenStore(format!("{} is not a valid token file: {}", self.path.display(), e)))?;
+
+        Ok(Some(token))
+    }
+
+    fn save(&self, token: &StoredToken) -> Result<(), SpotifyError> {
+        write_private(&self.path, &serde_json::to_vec(token).unwrap())
+    }
+
+    fn location(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl EncryptedFileTokenStore {
+
+    pub fn new(path: PathBuf, key: TokenKey) -> Self {
+        Self {
+            path,
+            key
+        }
+    }
+
+    /// Derives the encryption key from the passphrase or key file contents and the salt
+    ///
+    /// # Arguments
+    ///
+    /// * `salt` - Random salt stored alongside the encrypted token
+    fn derive_key(&self, salt: &[u8]) -> Result<Key, SpotifyError> {
+        let secret: Vec<u8> = match &self.key {
+            TokenKey::Passphrase(passphrase) => passphrase.as_bytes().to_vec(),
+            TokenKey::KeyFile(path) => fs::read(path).map_err(|e| SpotifyError::TokenStore(format!("Could not read token key file {}: {}", path.display(), e)))?
+        };
+
+        let mut key: Key = Key::default();
+        Argon2::default().hash_password_into(&secret, salt, &mut key).map_err(|e| SpotifyError::TokenStore(format!("Could not derive token key: {}", e)))?;
+
+        Ok(key)
+    }
+}
+
+impl TokenStore for EncryptedFileTokenStore {
+
+    fn load(&self) -> Result<Option<StoredToken>, SpotifyError> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+
+        // file is of the format <magic><salt><nonce><ciphertext>
+        let contents: Vec<u8> = fs::read(&self.path).map_err(|e| SpotifyError::TokenStore(format!("Could not read {}: {}", self.path.display(), e)))?;
+        if contents.len() < ENCRYPTED_MAGIC.len() + SALT_LENGTH + NONCE_LENGTH || !contents.starts_with(ENCRYPTED_MAGIC) {
+            return Err(SpotifyError::TokenStore(format!("{} is not an encrypted token file", self.path.display())));
+        }
+        let (salt, rest) = contents[ENCRYPTED_MAGIC.len()..].split_at(SALT_LENGTH);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LENGTH);
+
+        let cipher: ChaCha20Poly1305 = ChaCha20Poly1305::new(&self.derive_key(salt)?);
+        let plaintext: Vec<u8> = cipher.decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| SpotifyError::TokenStore(format!("Could not decrypt {}, check the passphrase or key file", self.path.display())))?;
+        let token: StoredToken = serde_json::from_slice(&plaintext).map_err(|e| SpotifyError::TokenStore(format!("{} is not a valid token file: {}", self.path.display(), e)))?;
+
+        Ok(Some(token))
+    }
+
+    fn save(&self, token: &StoredToken) -> Result<(), SpotifyError> {
+        let mut salt = [0u8; SALT_LENGTH];
+        let mut nonce = [0u8; NONCE_LENGTH];
+        rand::thread_rng().fill(&mut salt);
+        rand::thread_rng().fill(&mut nonce);
+
+        let cipher: ChaCha20Poly1305 = ChaCha20Poly1305::new(&self.derive_key(&salt)?);
+        let ciphertext: Vec<u8> = cipher.encrypt(Nonce::from_slice(&nonce), serde_json::to_vec(token).unwrap().as_slice())
+            .map_err(|_| SpotifyError::TokenStore("Could not encrypt token".to_string()))?;
+
+        let mut contents: Vec<u8> = Vec::with_capacity(ENCRYPTED_MAGIC.len() + SALT_LENGTH + NONCE_LENGTH + ciphertext.len());
+        contents.extend_from_slice(ENCRYPTED_MAGIC);
+        contents.extend_from_slice(&salt);
+        contents.extend_from_slice(&nonce);
+        contents.extend_from_slice(&ciphertext);
+
+        write_private(&self.path, &contents)
+    }
+
+    fn location(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Default folder for token files, the spotify-exporter folder of the user's config directory
+pub fn default_token_dir() -> PathBuf {
+    dirs::config_dir().unwrap_or_default().join("spotify-exporter")
+}
+
+/// Moves the plain text refresh token file written by older versions into the token store, if the store is still empty
+///
+/// # Arguments
+///
+/// * `token_store` - The store to move the token into
+/// * `legacy_path` - The token file of older versions
+pub fn migrate_legacy_token_file(token_store: &dyn TokenStore, legacy_path: &Path) -> Result<(), SpotifyError> {
+    if !legacy_path.exists() || token_store.load()?.is_some() {
+        return Ok(());
+    }
+
+    let refresh_token: String = fs::read_to_string(legacy_path).map_err(|e| SpotifyError::TokenStore(format!("Could not read {}: {}", legacy_path.display(), e)))?;
+    token_store.save(&StoredToken {
+        refresh_token: refresh_token.trim().to_owned(),
+        access_token: "".to_string(),
+        token_type: "".to_string(),
+        expires_at: 0,
+        scope: "".to_string()
+    })?;
+    fs::remove_file(legacy_path).map_err(|e| SpotifyError::TokenStore(format!("Could not remove {}: {}", legacy_path.display(), e)))?;
+    println!("Moved the refresh token from {} to {}", legacy_path.display(), token_store.location().display());
+
+    Ok(())
+}
+
+/// Writes the file so that only the current user can read it, replacing it atomically so a failed write never loses the old token
+///
+/// # Arguments
+///
+/// * `path` - The file to write
+/// * `contents` - The contents to write
+fn write_private(path: &Path, contents: &[u8]) -> Result<(), SpotifyError> {
+    let to_error = |e: std::io::Error| SpotifyError::TokenStore(format!("Could not write {}: {}", path.display(), e));
+
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        let mut dir_builder: DirBuilder = DirBuilder::new();
+        dir_builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut dir_builder, 0o700);
+        dir_builder.create(parent).map_err(to_error)?;
+    }
+
+    let temp_path: PathBuf = path.with_extension("tmp");
+    let mut options: OpenOptions = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(&temp_path).map_err(to_error)?;
+    // mode only applies when the file is created, so make sure a leftover temp file is restricted as well
+    #[cfg(unix)]
+    fs::set_permissions(&temp_path, std::os::unix::fs::PermissionsExt::from_mode(0o600)).map_err(to_error)?;
+    file.write_all(contents).map_err(to_error)?;
+    file.sync_all().map_err(to_error)?;
+
+    fs::rename(&temp_path, path).map_err(to_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir: PathBuf = std::env::temp_dir().join(format!("spotify_exporter_token_store_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn stored_token() -> StoredToken {
+        StoredToken {
+            refresh_token: "refresh".to_string(),
+            access_token: "access".to_string(),
+            token_type: "Bearer".to_string(),
+            expires_at: 1700000000,
+            scope: "user-library-read".to_string()
+        }
+    }
+
+    #[test]
+    fn encrypted_token_round_trips() {
+        let dir: PathBuf = temp_dir("round_trip");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("token.key"), "key file contents").unwrap();
+
+        let passphrase_store: EncryptedFileTokenStore = EncryptedFileTokenStore::new(dir.join("passphrase.enc"), TokenKey::Passphrase("secret".to_string()));
+        passphrase_store.save(&stored_token()).unwrap();
+        let key_file_store: EncryptedFileTokenStore = EncryptedFileTokenStore::new(dir.join("key_file.enc"), TokenKey::KeyFile(dir.join("token.key")));
+        key_file_store.save(&stored_token()).unwrap();
+
+        let tokens: Vec<StoredToken> = [passphrase_store.load(), key_file_store.load()].into_iter().map(|token| token.unwrap().unwrap()).collect();
+        let plain_text_stored: bool = fs::read(dir.join("passphrase.enc")).unwrap().windows(7).any(|window| window == b"refresh");
+        fs::remove_dir_all(&dir).unwrap();
+
+        for token in tokens {
+            assert_eq!((token.refresh_token.as_str(), token.access_token.as_str(), token.token_type.as_str()), ("refresh", "access", "Bearer"));
+            assert_eq!((token.expires_at, token.scope.as_str()), (1700000000, "user-library-read"));
+        }
+        assert!(!plain_text_stored);
+    }
+
+    #[test]
+    fn encrypted_token_with_wrong_passphrase_fails() {
+        let dir: PathBuf = temp_dir("wrong_passphrase");
+        EncryptedFileTokenStore::new(dir.join("token.enc"), TokenKey::Passphrase("secret".to_string())).save(&stored_token()).unwrap();
+
+        let loaded = EncryptedFileTokenStore::new(dir.join("token.enc"), TokenKey::Passphrase("not the secret".to_string())).load();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(loaded, Err(SpotifyError::TokenStore(error)) if error.starts_with("Could not decrypt")));
+    }
+
+    #[test]
+    fn short_or_unmarked_token_file_is_rejected() {
+        let dir: PathBuf = temp_dir("rejected");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("short.enc"), ENCRYPTED_MAGIC).unwrap();
+        fs::write(dir.join("unmarked.enc"), [0u8; 64]).unwrap();
+
+        let loaded: Vec<Result<Option<StoredToken>, SpotifyError>> = ["short.enc", "unmarked.enc"].iter()
+            .map(|file_name| EncryptedFileTokenStore::new(dir.join(file_name), TokenKey::Passphrase("secret".to_string())).load())
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        for result in loaded {
+            assert!(matches!(result, Err(SpotifyError::TokenStore(error)) if error.ends_with("is not an encrypted token file")));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn token_file_is_only_readable_by_the_user() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir: PathBuf = temp_dir("permissions");
+        fs::create_dir_all(&dir).unwrap();
+        // a token file left readable by others is replaced by a private one
+        fs::write(dir.join("token.json"), "{}").unwrap();
+        fs::set_permissions(dir.join("token.json"), fs::Permissions::from_mode(0o644)).unwrap();
+
+        PlainFileTokenStore::new(dir.join("token.json")).save(&stored_token()).unwrap();
+        write_private(&dir.join("nested").join("token.json"), b"{}").unwrap();
+        let modes: Vec<u32> = [dir.join("token.json"), dir.join("nested").join("token.json"), dir.join("nested")].iter()
+            .map(|path| fs::metadata(path).unwrap().permissions().mode() & 0o777)
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(modes, vec![0o600, 0o600, 0o700]);
+    }
+}
@@ -0,0 +1,385 @@
+use chrono::{DateTime, Datelike, Duration, Local, Timelike};
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::Value;
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{gdpr::Play, manifest::{ExportSource, Manifest}};
+
+/// Categories of saved items with when they were saved, and the key of the saved object within an item
+const SAVED_CATEGORIES: [(&str, &str); 4] = [("tracks", "track"), ("albums", "album"), ("shows", "show"), ("episodes", "episode")];
+
+const WEEKDAYS: [&str; 7] = ["Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday", "Sunday"];
+
+/// How reports are printed
+#[derive(Clone, Copy, ValueEnum)]
+pub enum StatsFormat {
+    /// readable text tables
+    Table,
+    /// JSON, for further processing
+    Json
+}
+
+/// Statistics of the listening history
+#[derive(Serialize)]
+pub struct HistoryReport {
+    pub plays: usize,
+    pub minutes: u64,
+    /// Share of the plays that were skipped, of the plays the extended history records skips for
+    pub skip_rate: Option<f64>,
+    pub minutes_per_year: BTreeMap<String, u64>,
+    pub minutes_per_month: BTreeMap<String, u64>,
+    pub top_artists: Vec<Ranked>,
+    pub top_tracks: Vec<Ranked>,
+    pub top_albums: Vec<Ranked>,
+    pub top_shows: Vec<Ranked>,
+    /// Minutes by the hour of the day playback started, in the local time zone
+    pub minutes_per_hour: Vec<u64>,
+    /// Minutes by the day of the week playback started, Monday first, in the local time zone
+    pub minutes_per_weekday: Vec<u64>
+}
+
+/// A top artist, track, album or show
+#[derive(Serialize)]
+pub struct Ranked {
+    pub name: String,
+    pub minutes: u64,
+    pub plays: usize,
+    pub skip_rate: Option<f64>
+}
+
+/// Statistics of the library of an export
+#[derive(Serialize)]
+pub struct LibraryReport {
+    /// Number of items per category
+    pub categories: BTreeMap<String, usize>,
+    /// Artists credited on the most liked songs
+    pub top_artists: Vec<Counted>,
+    /// Number of liked songs per decade their album was released in
+    pub release_decades: BTreeMap<String, usize>,
+    /// Number of liked songs per genre of their artists, as far as the export holds the genres of the artists
+    pub genres: Vec<Counted>,
+    /// Liked songs none of whose artists have known genres
+    pub tracks_without_genres: usize,
+    /// Share of the liked songs that are explicit
+    pub explicit_share: Option<f64>,
+    /// Total duration of the liked songs
+    pub liked_minutes: u64,
+    /// The first and last saved item per category
+    pub saved: Vec<SavedRange>
+}
+
+/// An artist or genre and the number of liked songs it counts
+#[derive(Serialize)]
+pub struct Counted {
+    pub name: String,
+    pub tracks: usize
+}
+
+/// The first and last item saved of a category
+#[derive(Serialize)]
+pub struct SavedRange {
+    pub category: String,
+    pub oldest: Saved,
+    pub newest: Saved
+}
+
+/// A saved item and when it was saved
+#[derive(Serialize)]
+pub struct Saved {
+    pub name: String,
+    pub added_at: String
+}
+
+/// Running totals of a top artist, track, album or show
+#[derive(Default)]
+struct Tally {
+    ms_played: u64,
+    plays: usize,
+    /// Plays that were skipped and plays the skips are known of
+    skips: usize,
+    skips_known: usize
+}
+
+impl Tally {
+
+    fn add(&mut self, play: &Play) {
+        self.ms_played += play.ms_played;
+        self.plays += 1;
+        if let Some(skipped) = skipped(play) {
+            self.skips_known += 1;
+            self.skips += usize::from(skipped);
+        }
+    }
+
+    fn skip_rate(&self) -> Option<f64> {
+        (self.skips_known > 0).then(|| self.skips as f64 / self.skips_known as f64)
+    }
+}
+
+/// Computes the statistics of the listening history
+///
+/// # Arguments
+///
+/// * `plays` - The plays of the streaming history
+/// * `top` - How many artists, tracks, albums and shows to rank
+pub fn history_report(plays: &[Play], top: usize) -> HistoryReport {
+    let mut total: Tally = Tally::default();
+    let mut ms_per_year: BTreeMap<String, u64> = BTreeMap::new();
+    let mut ms_per_month: BTreeMap<String, u64> = BTreeMap::new();
+    let mut ms_per_hour: [u64; 24] = [0; 24];
+    let mut ms_per_weekday: [u64; 7] = [0; 7];
+    let mut artists: HashMap<String, Tally> = HashMap::new();
+    let mut tracks: HashMap<String, Tally> = HashMap::new();
+    let mut albums: HashMap<String, Tally> = HashMap::new();
+    let mut shows: HashMap<String, Tally> = HashMap::new();
+
+    for play in plays {
+        total.add(play);
+
+        // plays are grouped by when they started in the local time zone, so a play near midnight falls in the same period in every table
+        if let Ok(ended_at) = DateTime::parse_from_rfc3339(&play.ended_at) {
+            let started_at: DateTime<Local> = (ended_at - Duration::milliseconds(play.ms_played.try_into().unwrap_or(0))).with_timezone(&Local);
+            *ms_per_year.entry(started_at.format("%Y").to_string()).or_default() += play.ms_played;
+            *ms_per_month.entry(started_at.format("%Y-%m").to_string()).or_default() += play.ms_played;
+            ms_per_hour[started_at.hour() as usize] += play.ms_played;
+            ms_per_weekday[started_at.weekday().num_days_from_monday() as usize] += play.ms_played;
+        }
+
+        if let (Some(artist), Some(track)) = (&play.artist_name, &play.track_name) {
+            artists.entry(artist.clone()).or_default().add(play);
+            tracks.entry(format!("{} - {}", artist, track)).or_default().add(play);
+            if let Some(album) = &play.album_name {
+                albums.entry(format!("{} - {}", artist, album)).or_default().add(play);
+            }
+        } else if let Some(show) = &play.show_name {
+            shows.entry(show.clone()).or_default().add(play);
+        }
+    }
+
+    HistoryReport {
+        plays: total.plays,
+        minutes: minutes(total.ms_played),
+        skip_rate: total.skip_rate(),
+        minutes_per_year: ms_per_year.into_iter().map(|(year, ms_played)| (year, minutes(ms_played))).collect(),
+        minutes_per_month: ms_per_month.into_iter().map(|(month, ms_played)| (month, minutes(ms_played))).collect(),
+        top_artists: rank(artists, top),
+        top_tracks: rank(tracks, top),
+        top_albums: rank(albums, top),
+        top_shows: rank(shows, top),
+        minutes_per_hour: ms_per_hour.into_iter().map(minutes).collect(),
+        minutes_per_weekday: ms_per_weekday.into_iter().map(minutes).collect()
+    }
+}
+
+/// Computes the statistics of the library of an export
+///
+/// # Arguments
+///
+/// * `export_source` - The export to report on
+/// * `manifest` - The manifest of the export
+/// * `top` - How many artists and genres to rank
+pub fn library_report(export_source: &mut ExportSource, manifest: &Manifest, top: usize) -> Result<LibraryReport, String> {
+    let categories: BTreeMap<String, usize> = manifest.categories.iter().map(|manifest_category| (manifest_category.category.clone(), manifest_category.items)).collect();
+
+    // only full artist objects have genres, the artists of tracks are simplified
+    let mut artist_genres: HashMap<String, Vec<String>> = HashMap::new();
+    let followed_artists: Value = export_source.read_category(manifest, "artists")?;
+    let artists_catalog: Value = export_source.read_category(manifest, "artists_catalog")?;
+    for artist in followed_artists.as_array().into_iter().flatten().chain(artists_catalog.as_array().into_iter().flatten()) {
+        if let (Some(id), Some(genres)) = (artist["id"].as_str(), artist["genres"].as_array()) {
+            artist_genres.insert(id.to_owned(), genres.iter().filter_map(Value::as_str).map(str::to_owned).collect());
+        }
+    }
+
+    let liked_tracks: Value = export_source.read_category(manifest, "tracks")?;
+    let liked_tracks: &[Value] = liked_tracks.as_array().map(Vec::as_slice).unwrap_or_default();
+    let mut artists: HashMap<String, usize> = HashMap::new();
+    let mut release_decades: BTreeMap<String, usize> = BTreeMap::new();
+    let mut genres: HashMap<String, usize> = HashMap::new();
+    let mut tracks_without_genres: usize = 0;
+    let mut explicit: usize = 0;
+    let mut liked_ms: u64 = 0;
+
+    for item in liked_tracks {
+        let track: &Value = &item["track"];
+        liked_ms += track["duration_ms"].as_u64().unwrap_or(0);
+        explicit += usize::from(track["explicit"].as_bool().unwrap_or(false));
+
+        // release dates are a year, a month or a day depending on their precision, the year always comes first
+        if let Some(year) = track["album"]["release_date"].as_str().and_then(|release_date| release_date.get(..4)).and_then(|year| year.parse::<u32>().ok()) {
+            *release_decades.entry(format!("{}s", year / 10 * 10)).or_default() += 1;
+        }
+
+        let mut track_genres: Vec<&String> = Vec::new();
+        for artist in track["artists"].as_array().into_iter().flatten() {
+            if let Some(name) = artist["name"].as_str() {
+                *artists.entry(name.to_owned()).or_default() += 1;
+            }
+            track_genres.extend(artist["id"].as_str().and_then(|id| artist_genres.get(id)).into_iter().flatten());
+        }
+        track_genres.sort();
+        track_genres.dedup();
+        if track_genres.is_empty() {
+            tracks_without_genres += 1;
+        }
+        for genre in track_genres {
+            *genres.entry(genre.clone()).or_default() += 1;
+        }
+    }
+
+    let mut saved: Vec<SavedRange> = Vec::new();
+    for (category, item_object) in SAVED_CATEGORIES {
+        let items: Value = export_source.read_category(manifest, category)?;
+        let mut items: Vec<&Value> = items.as_array().into_iter().flatten().filter(|item| item["added_at"].is_string()).collect();
+        items.sort_by_key(|item| item["added_at"].as_str());
+
+        if let (Some(oldest), Some(newest)) = (items.first(), items.last()) {
+            saved.push(SavedRange {
+                category: category.to_owned(),
+                oldest: saved_item(oldest, item_object),
+                newest: saved_item(newest, item_object)
+            });
+        }
+    }
+
+    Ok(LibraryReport {
+        categories,
+        top_artists: count_top(artists, top),
+        release_decades,
+        genres: count_top(genres, top),
+        tracks_without_genres,
+        explicit_share: (!liked_tracks.is_empty()).then(|| explicit as f64 / liked_tracks.len() as f64),
+        liked_minutes: minutes(liked_ms),
+        saved
+    })
+}
+
+fn saved_item(item: &Value, item_object: &str) -> Saved {
+    Saved {
+        name: item[item_object]["name"].as_str().unwrap_or("unknown").to_owned(),
+        added_at: item["added_at"].as_str().unwrap().to_owned()
+    }
+}
+
+/// Ranks by number of liked songs, most first
+fn count_top(counts: HashMap<String, usize>, top: usize) -> Vec<Counted> {
+    let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+    counts.sort_by(|(a_name, a), (b_name, b)| b.cmp(a).then_with(|| a_name.cmp(b_name)));
+
+    counts.into_iter().take(top).map(|(name, tracks)| Counted { name, tracks }).collect()
+}
+
+impl LibraryReport {
+
+    /// Prints the report as readable text tables
+    pub fn print_table(&self) {
+        println!("{} minutes of liked songs, {} explicit", self.liked_minutes, percentage(self.explicit_share));
+
+        print_counts("Category", "Items", self.categories.iter().map(|(category, items)| (category.as_str(), *items)));
+        print_counts("Artist", "Tracks", self.top_artists.iter().map(|counted| (counted.name.as_str(), counted.tracks)));
+        print_counts("Decade", "Tracks", self.release_decades.iter().map(|(decade, tracks)| (decade.as_str(), *tracks)));
+        print_counts("Genre", "Tracks", self.genres.iter().map(|counted| (counted.name.as_str(), counted.tracks)));
+        if self.tracks_without_genres > 0 {
+            println!("{} liked songs have no artist with known genres", self.tracks_without_genres);
+        }
+
+        if !self.saved.is_empty() {
+            println!();
+        }
+        for saved_range in &self.saved {
+            println!("{} saved first {} ({}), last {} ({})", saved_range.category, saved_range.oldest.name, saved_range.oldest.added_at, saved_range.newest.name, saved_range.newest.added_at);
+        }
+    }
+}
+
+impl HistoryReport {
+
+    /// Prints the report as readable text tables
+    pub fn print_table(&self) {
+        println!("{} plays, {} minutes, skip rate {}", self.plays, self.minutes, percentage(self.skip_rate));
+
+        print_minutes("Year", &self.minutes_per_year);
+        print_minutes("Month", &self.minutes_per_month);
+        print_ranked("Artist", &self.top_artists);
+        print_ranked("Track", &self.top_tracks);
+        print_ranked("Album", &self.top_albums);
+        print_ranked("Show", &self.top_shows);
+
+        let minutes_per_hour: BTreeMap<String, u64> = self.minutes_per_hour.iter().enumerate().map(|(hour, minutes)| (format!("{:02}:00", hour), *minutes)).collect();
+        print_minutes("Hour", &minutes_per_hour);
+        // weekdays are printed in week order rather than by name
+        println!();
+        println!("{:<10} {:>10}", "Weekday", "Minutes");
+        for (weekday, minutes) in WEEKDAYS.iter().zip(&self.minutes_per_weekday) {
+            println!("{:<10} {:>10}", weekday, minutes);
+        }
+    }
+}
+
+fn skipped(play: &Play) -> Option<bool> {
+    // only the extended history records skips, older packages leave skipped out but end skipped plays with fwdbtn
+    play.extended.then(|| play.skipped == Some(true) || play.reason_end.as_deref() == Some("fwdbtn"))
+}
+
+fn minutes(ms_played: u64) -> u64 {
+    ms_played / 60_000
+}
+
+/// Ranks by time played, most first
+fn rank(tallies: HashMap<String, Tally>, top: usize) -> Vec<Ranked> {
+    let mut tallies: Vec<(String, Tally)> = tallies.into_iter().collect();
+    tallies.sort_by(|(a_name, a), (b_name, b)| b.ms_played.cmp(&a.ms_played).then_with(|| a_name.cmp(b_name)));
+
+    tallies.into_iter().take(top).map(|(name, tally)| Ranked {
+        name,
+        minutes: minutes(tally.ms_played),
+        plays: tally.plays,
+        skip_rate: tally.skip_rate()
+    }).collect()
+}
+
+fn percentage(rate: Option<f64>) -> String {
+    rate.map_or("unknown".to_string(), |rate| format!("{:.1}%", rate * 100.0))
+}
+
+/// Prints a table of minutes, skipped if there is nothing to print
+fn print_minutes(heading: &str, minutes: &BTreeMap<String, u64>) {
+    if minutes.is_empty() {
+        return;
+    }
+
+    println!();
+    println!("{:<10} {:>10}", heading, "Minutes");
+    for (key, minutes) in minutes {
+        println!("{:<10} {:>10}", key, minutes);
+    }
+}
+
+fn print_ranked(heading: &str, ranked: &[Ranked]) {
+    if ranked.is_empty() {
+        return;
+    }
+
+    let width: usize = ranked.iter().map(|ranked| ranked.name.chars().count()).max().unwrap_or(0).max(heading.len());
+    println!();
+    println!("{:>3}  {:<width$}  {:>10}  {:>6}  {:>7}", "#", heading, "Minutes", "Plays", "Skipped");
+    for (index, ranked) in ranked.iter().enumerate() {
+        println!("{:>3}  {:<width$}  {:>10}  {:>6}  {:>7}", index + 1, ranked.name, ranked.minutes, ranked.plays, percentage(ranked.skip_rate));
+    }
+}
+
+fn print_counts<'a>(heading: &str, unit: &str, counts: impl Iterator<Item = (&'a str, usize)>) {
+    let counts: Vec<(&str, usize)> = counts.collect();
+    if counts.is_empty() {
+        return;
+    }
+
+    let width: usize = counts.iter().map(|(name, _)| name.chars().count()).max().unwrap_or(0).max(heading.len());
+    println!();
+    println!("{:<width$}  {:>8}", heading, unit);
+    for (name, count) in counts {
+        println!("{:<width$}  {:>8}", name, count);
+    }
+}
@@ -0,0 +1,170 @@
+use serde_json::Value;
+
+use std::path::Path;
+
+use crate::manifest::{self, ExportSource, Manifest, ManifestCategory, MANIFEST_FILE};
+
+/// Outcome of verifying an export
+pub struct Verification {
+    /// Number of categories listed in the manifest, or found in an export without one
+    pub categories: usize,
+    /// Whether the export has a manifest, exports of older versions have none and so no checksums or totals to compare
+    pub has_manifest: bool,
+    /// Everything found wrong with the export, empty if it is intact
+    pub problems: Vec<String>
+}
+
+/// Verifies an export against its manifest, checking every file is present, unchanged, parses and holds the expected items
+///
+/// Exports of older versions have no manifest, their files are only checked to parse and hold the expected items
+///
+/// # Arguments
+///
+/// * `path` - An export folder or archive
+/// * `passphrase` - The passphrase to decrypt the archive with, if it is encrypted
+///
+/// # Returns
+/// What was found wrong with the export, or an error if it could not be read at all
+pub fn verify_export(path: &Path, passphrase: Option<String>) -> Result<Verification, String> {
+    let mut export_source: ExportSource = ExportSource::open(path, passphrase)?;
+    if !export_source.file_names().iter().any(|file_name| file_name == MANIFEST_FILE) {
+        return Ok(verify_without_manifest(&mut export_source));
+    }
+    let manifest: Manifest = export_source.read_manifest()?;
+
+    let mut problems: Vec<String> = Vec::new();
+    for manifest_category in &manifest.categories {
+        problems.append(&mut verify_category(&mut export_source, manifest_category));
+    }
+
+    for file_name in export_source.file_names() {
+        if file_name != MANIFEST_FILE && !manifest.categories.iter().any(|manifest_category| manifest_category.file == file_name) {
+            problems.push(format!("{}: not listed in the manifest", file_name));
+        }
+    }
+
+    Ok(Verification {
+        categories: manifest.categories.len(),
+        has_manifest: true,
+        problems
+    })
+}
+
+/// Verifies an export of an older version, which names its files <category>_<YYYYMMDD>.json and has no manifest
+fn verify_without_manifest(export_source: &mut ExportSource) -> Verification {
+    let mut categories: usize = 0;
+    let mut problems: Vec<String> = Vec::new();
+    for file_name in export_source.file_names() {
+        let Some(category) = legacy_category(&file_name) else {
+            problems.push(format!("{}: not named <category>_<YYYYMMDD>.json", file_name));
+            continue;
+        };
+        categories += 1;
+
+        let data: Value = match export_source.read(&file_name).and_then(|contents| serde_json::from_slice(&contents).map_err(|e| format!("is not valid JSON({})", e))) {
+            Ok(data) => data,
+            Err(e) => {
+                problems.push(format!("{}: {}", file_name, e));
+                continue;
+            }
+        };
+        if let Err(e) = check_schema(category, &data) {
+            problems.push(format!("{}: {}", file_name, e));
+        }
+    }
+
+    Verification {
+        categories,
+        has_manifest: false,
+        problems
+    }
+}
+
+/// Gives the category of a file named <category>_<YYYYMMDD>.json, as older versions named them
+fn legacy_category(file_name: &str) -> Option<&str> {
+    let (category, date) = file_name.strip_suffix(".json")?.rsplit_once('_')?;
+    (date.len() == 8 && date.chars().all(|c| c.is_ascii_digit()) && !category.is_empty()).then_some(category)
+}
+
+/// Verifies a single category file against its manifest entry
+fn verify_category(export_source: &mut ExportSource, manifest_category: &ManifestCategory) -> Vec<String> {
+    let file: &str = &manifest_category.file;
+    let contents: Vec<u8> = match export_source.read(file) {
+        Ok(contents) => contents,
+        Err(e) => return vec![format!("{}: could not be read({})", file, e)]
+    };
+
+    let mut problems: Vec<String> = Vec::new();
+    if contents.len() != manifest_category.bytes {
+        problems.push(format!("{}: is {} bytes but the manifest records {}", file, contents.len(), manifest_category.bytes));
+    }
+    if manifest::sha256_hex(&contents) != manifest_category.sha256 {
+        problems.push(format!("{}: checksum does not match the manifest", file));
+    }
+
+    let data: Value = match serde_json::from_slice(&contents) {
+        Ok(data) => data,
+        Err(e) => {
+            problems.push(format!("{}: is not valid JSON({})", file, e));
+            return problems;
+        }
+    };
+    match check_schema(&manifest_category.category, &data) {
+        Ok(Some(items)) if items != manifest_category.items => problems.push(format!("{}: holds {} items but the manifest records {}", file, items, manifest_category.items)),
+        Ok(_) => {}
+        Err(e) => problems.push(format!("{}: {}", file, e))
+    }
+
+    if let Some(total) = manifest_category.total.filter(|total| *total != manifest_category.items) {
+        problems.push(format!("{}: {} items were exported but Spotify reported {}", file, manifest_category.items, total));
+    }
+
+    problems
+}
+
+/// Checks the file holds what is expected for its category
+///
+/// # Arguments
+///
+/// * `category` - The category of the file
+/// * `data` - The parsed contents of the file
+///
+/// # Returns
+/// The number of items in the file, or none if the category does not hold a list of items
+fn check_schema(category: &str, data: &Value) -> Result<Option<usize>, String> {
+    // the profile is a single object rather than a list of items
+    if category == "profile" {
+        if !data[category]["id"].is_string() {
+            return Err("expected a profile with an id".to_string());
+        }
+        return Ok(None);
+    }
+
+    // saved items wrap the saved object along with when it was added, the others are the objects themselves
+    let item_object: Option<&str> = match category {
+        "tracks" => Some("track"),
+        "albums" => Some("album"),
+        "episodes" => Some("episode"),
+        "shows" => Some("show"),
+        "recently_played" | "top_tracks" => Some("track"),
+        "top_artists" => Some("artist"),
+        "audiobooks" | "playlists" | "artists" | "artists_catalog" => None,
+        _ => return Ok(data[category].as_array().map(Vec::len))
+    };
+
+    let items: &Vec<Value> = data[category].as_array().ok_or(format!("expected a {} list", category))?;
+    for (index, item) in items.iter().enumerate() {
+        let object: &Value = item_object.map_or(item, |item_object| &item[item_object]);
+        if !object["id"].is_string() {
+            return Err(match item_object {
+                Some(item_object) => format!("item {} has no {} with an id", index, item_object),
+                None => format!("item {} has no id", index)
+            });
+        }
+        if category == "playlists" && !object["tracks"].is_array() {
+            return Err(format!("playlist {} has no list of tracks", object["id"].as_str().unwrap()));
+        }
+    }
+
+    Ok(Some(items.len()))
+}